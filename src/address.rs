@@ -8,7 +8,6 @@ use thiserror::Error;
 
 #[cfg(feature = "i2p")]
 use i2p::net::{I2pSocketAddr, ToI2pSocketAddrs};
-#[cfg(any(feature = "tor", feature = "i2p"))]
 use koibumi_base32 as base32;
 
 pub trait ToPeerAddrs {
@@ -118,6 +117,29 @@ pub enum ParseError {
   UnrecognizedAddressFormat,
   #[error("Address is missing port")]
   MissingPort,
+  #[error("Invalid onion v3 address `{0}`")]
+  InvalidOnionV3(String),
+}
+
+/// Check that `address`, without ".onion", is a v2 (16 characters)
+/// or v3 (56 characters) base32 onion address.
+pub(crate) fn check_onion(address: &str) -> Result<(), ParseError> {
+  let decoded = base32::decode(address.to_lowercase());
+  match address.len() {
+    16 if decoded.is_ok() => Ok(()),
+    16 => Err(ParseError::UnrecognizedAddressFormat),
+    // 32 bytes of public key, 2 of checksum and the version,
+    // the checksum would need sha3 so only the rest is checked
+    56 => match decoded {
+      Ok(bytes) if bytes.len() == 35 && bytes[34] == 3 => Ok(()),
+      _ => Err(ParseError::InvalidOnionV3(address.to_string())),
+    },
+    length => Err(ParseError::WrongLength {
+      address:  address.to_string(),
+      length,
+      expected: "16 or 56".to_string(),
+    }),
+  }
 }
 
 #[derive(Debug, Error)]
@@ -132,6 +154,8 @@ pub enum AddressError {
   IoError(#[from] std::io::Error),
   #[error("Address is of an invalid type")]
   InvalidAddressType,
+  #[error("Peer db flag {0} does not match the packed length")]
+  DbFlagMismatch(u8),
 }
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -148,6 +172,106 @@ pub enum PeerAddr {
   Loki(String, u16),
 }
 
+/// The transport family of a `PeerAddr`, available regardless of
+/// which transports are enabled so it can come from configuration.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum AddrFamily {
+  IPV4,
+  IPV6,
+  Onion,
+  I2P,
+  Loki,
+}
+
+/// The transport families enabled by cargo features, clearnet
+/// is always available.
+/// ```
+/// use zeronet_protocol::address::AddrFamily;
+///
+/// let transports = zeronet_protocol::supported_transports();
+/// assert!(transports.contains(&AddrFamily::IPV4));
+/// ```
+pub fn supported_transports() -> &'static [AddrFamily] {
+  &[
+    AddrFamily::IPV4,
+    AddrFamily::IPV6,
+    #[cfg(feature = "tor")]
+    AddrFamily::Onion,
+    #[cfg(feature = "i2p")]
+    AddrFamily::I2P,
+    #[cfg(feature = "loki")]
+    AddrFamily::Loki,
+  ]
+}
+
+/// The proxies available for dialing overlay addresses,
+/// see `PeerAddr::is_routable`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransportConfig {
+  pub tor_proxy:  Option<SocketAddr>,
+  pub i2p_proxy:  Option<SocketAddr>,
+  pub loki_proxy: Option<SocketAddr>,
+}
+
+impl TransportConfig {
+  /// The proxy to dial `family` through, `None` for clearnet
+  /// and for overlays without one configured.
+  pub fn proxy(&self, family: AddrFamily) -> Option<SocketAddr> {
+    match family {
+      AddrFamily::IPV4 | AddrFamily::IPV6 => None,
+      AddrFamily::Onion => self.tor_proxy,
+      AddrFamily::I2P => self.i2p_proxy,
+      AddrFamily::Loki => self.loki_proxy,
+    }
+  }
+}
+
+/// A host pattern to filter addresses with, see `PeerAddr::matches`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrPattern {
+  /// IP addresses whose first `prefix` bits equal the given address
+  Cidr(IpAddr, u8),
+  /// One overlay host such as `abc.onion`, compared case-insensitively
+  Host(String),
+}
+
+impl AddrPattern {
+  /// Parse a CIDR range like `192.168.0.0/16`, a single IP
+  /// or an overlay host, ports are not part of a pattern
+  /// ```
+  /// use zeronet_protocol::address::AddrPattern;
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let pattern = AddrPattern::parse("192.168.0.0/16").unwrap();
+  /// assert!(PeerAddr::parse("192.168.1.1:15441").unwrap().matches(&pattern));
+  /// ```
+  pub fn parse(pattern: &str) -> Result<AddrPattern, ParseError> {
+    let pattern = pattern.trim();
+    if let Some((ip, prefix)) = pattern.split_once('/') {
+      let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|_| ParseError::UnrecognizedAddressFormat)?;
+      let prefix: u8 = prefix.parse()?;
+      let max = if ip.is_ipv4() { 32 } else { 128 };
+      if prefix > max {
+        return Err(ParseError::UnrecognizedAddressFormat);
+      }
+      return Ok(AddrPattern::Cidr(ip, prefix));
+    }
+    if let Ok(ip) = pattern.parse::<IpAddr>() {
+      let prefix = if ip.is_ipv4() { 32 } else { 128 };
+      return Ok(AddrPattern::Cidr(ip, prefix));
+    }
+    if pattern.is_empty() || pattern.contains(':') {
+      return Err(ParseError::UnrecognizedAddressFormat);
+    }
+    Ok(AddrPattern::Host(pattern.to_lowercase()))
+  }
+}
+
+/// IPv6 flowinfo and scope ids are dropped, peers only exchange
+/// the 16 address bytes and the port, so link-local addresses lose
+/// their zone.
 impl From<SocketAddr> for PeerAddr {
   fn from(address: SocketAddr) -> PeerAddr {
     match address {
@@ -179,11 +303,11 @@ impl TryInto<SocketAddr> for &PeerAddr {
   fn try_into(self) -> Result<SocketAddr, Self::Error> {
     match self {
       PeerAddr::IPV4(ip, port) => Ok(SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::from(ip.clone())),
+        IpAddr::V4(Ipv4Addr::from(*ip)),
         *port,
       )),
       PeerAddr::IPV6(ip, port) => Ok(SocketAddr::new(
-        IpAddr::V6(Ipv6Addr::from(ip.clone())),
+        IpAddr::V6(Ipv6Addr::from(*ip)),
         *port,
       )),
       #[cfg(any(feature = "i2p", feature = "tor"))]
@@ -192,6 +316,9 @@ impl TryInto<SocketAddr> for &PeerAddr {
   }
 }
 
+/// The reading and writing halves of a connection to a peer.
+pub type StreamPair = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
 impl PeerAddr {
   /// Create an address by parsing a string
   /// ```
@@ -217,14 +344,10 @@ impl PeerAddr {
 
       #[cfg(feature = "tor")]
       if let Some(address) = parts[0].strip_suffix(".onion") {
+        check_onion(address)?;
         return match address.len() {
           16 => Ok(PeerAddr::OnionV2(address.to_string(), port)),
-          56 => Ok(PeerAddr::OnionV3(address.to_string(), port)),
-          l => Err(ParseError::WrongLength {
-            address:  address.to_string(),
-            length:   l,
-            expected: "16 or 56".to_string(),
-          }),
+          _ => Ok(PeerAddr::OnionV3(address.to_string(), port)),
         };
       }
       #[cfg(feature = "i2p")]
@@ -240,6 +363,73 @@ impl PeerAddr {
     Err(ParseError::UnrecognizedAddressFormat)
   }
 
+  /// Like `parse`, but first trims whitespace and drops
+  /// a trailing slash or path, for addresses from messy sources
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse_lenient(" 127.0.0.1:8001/\n").unwrap();
+  /// assert_eq!(address, PeerAddr::parse("127.0.0.1:8001").unwrap());
+  /// ```
+  pub fn parse_lenient(address: &str) -> Result<PeerAddr, ParseError> {
+    let address = address.trim();
+    let address = match address.find('/') {
+      Some(index) => &address[..index],
+      None => address,
+    };
+    PeerAddr::parse(address.trim_end())
+  }
+
+  /// An IPv4 address from its octets.
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// assert_eq!(PeerAddr::ipv4(127, 0, 0, 1, 15441).to_string(), "127.0.0.1:15441");
+  /// ```
+  pub fn ipv4(a: u8, b: u8, c: u8, d: u8, port: u16) -> PeerAddr {
+    PeerAddr::IPV4([a, b, c, d], port)
+  }
+
+  /// An IPv6 address from its eight 16-bit segments.
+  pub fn ipv6(segments: [u16; 8], port: u16) -> PeerAddr {
+    let [a, b, c, d, e, f, g, h] = segments;
+    PeerAddr::from_ip(IpAddr::V6(Ipv6Addr::new(a, b, c, d, e, f, g, h)), port)
+  }
+
+  /// A clearnet address from either kind of IP.
+  pub fn from_ip(ip: IpAddr, port: u16) -> PeerAddr {
+    PeerAddr::from(SocketAddr::new(ip, port))
+  }
+
+  /// Parse newline separated `host:port` peers, as found in ZeroNet
+  /// config files, skipping blank lines. Lines that fail to parse
+  /// are returned with their error instead.
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let (peers, errors) = PeerAddr::parse_list("127.0.0.1:8001\n\n[::1]:8002\n");
+  /// assert_eq!(peers.len(), 2);
+  /// assert!(errors.is_empty());
+  /// assert_eq!(PeerAddr::to_list(&peers), "127.0.0.1:8001\n[::1]:8002\n");
+  /// ```
+  pub fn parse_list(text: &str) -> (Vec<PeerAddr>, Vec<(String, ParseError)>) {
+    let mut peers = vec![];
+    let mut errors = vec![];
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+      match PeerAddr::parse(line) {
+        Ok(peer) => peers.push(peer),
+        Err(err) => errors.push((line.to_string(), err)),
+      }
+    }
+    (peers, errors)
+  }
+
+  /// Write `peers` one `host:port` per line, see `parse_list`.
+  /// `Display` adds the transport, so this uses `to_string`.
+  pub fn to_list(peers: &[PeerAddr]) -> String {
+    peers.iter().map(|peer| peer.to_string() + "\n").collect()
+  }
+
   /// Unpack the address from bytes
   /// ```
   /// use zeronet_protocol::PeerAddr;
@@ -271,7 +461,7 @@ impl PeerAddr {
         let port = u16::from_le_bytes(bytes[10..12].try_into().unwrap());
         let mut array = [0u8; 10];
         array.copy_from_slice(&bytes[..10]);
-        let address = base32::encode(&array);
+        let address = base32::encode(array);
         Ok(PeerAddr::OnionV2(address, port))
       }
       #[cfg(feature = "i2p")]
@@ -279,7 +469,7 @@ impl PeerAddr {
         let port = u16::from_le_bytes(bytes[32..34].try_into().unwrap());
         let mut array = [0u8; 32];
         array.copy_from_slice(&bytes[..32]);
-        let address = base32::encode(&array);
+        let address = base32::encode(array);
         Ok(PeerAddr::I2PB32(address, port))
       }
       #[cfg(feature = "tor")]
@@ -287,14 +477,40 @@ impl PeerAddr {
         let port = u16::from_le_bytes(bytes[35..37].try_into().unwrap());
         let mut array = [0u8; 35];
         array.copy_from_slice(&bytes[..35]);
-        let address = base32::encode(&array);
+        let address = base32::encode(array);
         Ok(PeerAddr::OnionV3(address, port))
       }
       l => Err(AddressError::InvalidBytearray(l)),
     }
   }
 
-  /// Pack the address into bytes
+  /// Unpack an address stored in a peer db, `bytes` packed as by
+  /// `pack`. ZeroNet has no transport flag, it tells packed
+  /// addresses apart by their length like `unpack` does, so the
+  /// flag is the packed length and only checked against `bytes`.
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::from_db_entry(6, &[127, 0, 0, 1, 225, 16]).unwrap();
+  /// assert_eq!(address.to_string(), "127.0.0.1:4321".to_string());
+  /// ```
+  pub fn from_db_entry(flag: u8, bytes: &[u8]) -> Result<PeerAddr, AddressError> {
+    if flag as usize != bytes.len() {
+      return Err(AddressError::DbFlagMismatch(flag));
+    }
+    PeerAddr::unpack(bytes)
+  }
+
+  /// The flag and packed bytes to store the address
+  /// in a peer db, see `from_db_entry`.
+  pub fn to_db_entry(&self) -> (u8, Vec<u8>) {
+    let bytes = self.pack();
+    (bytes.len() as u8, bytes)
+  }
+
+  /// Pack the address into bytes: the host followed by the port
+  /// as two little-endian bytes, like ZeroNet's `struct.pack("H")`
+  /// on the little-endian machines it runs on.
   /// ```
   /// use zeronet_protocol::PeerAddr;
   ///
@@ -304,45 +520,33 @@ impl PeerAddr {
   /// assert_eq!(packed, [127, 0, 0, 1, 225, 16]);
   /// ```
   pub fn pack(&self) -> Vec<u8> {
+    let mut bytes = self.host_bytes();
+    bytes.append(&mut self.get_port().to_le_bytes().to_vec());
+    bytes
+  }
+
+  /// The packed address without its port
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
+  /// assert_eq!(address.host_bytes(), [127, 0, 0, 1]);
+  /// ```
+  pub fn host_bytes(&self) -> Vec<u8> {
     match self {
-      PeerAddr::IPV4(address, port) => {
-        let mut bytes = address.to_vec();
-        bytes.append(&mut port.to_le_bytes().to_vec());
-        bytes
-      }
-      PeerAddr::IPV6(address, port) => {
-        let mut bytes = address.to_vec();
-        bytes.append(&mut port.to_le_bytes().to_vec());
-        bytes
-      }
+      PeerAddr::IPV4(address, _) => address.to_vec(),
+      PeerAddr::IPV6(address, _) => address.to_vec(),
       #[cfg(feature = "tor")]
-      PeerAddr::OnionV2(address, port) => {
-        let address = address.to_lowercase();
-        let mut bytes = base32::decode(address).unwrap();
-        bytes.append(&mut port.to_le_bytes().to_vec());
-        bytes
-      }
+      PeerAddr::OnionV2(address, _) => base32::decode(address.to_lowercase()).unwrap(),
       #[cfg(feature = "tor")]
-      PeerAddr::OnionV3(address, port) => {
-        let address = address.to_lowercase();
-        let mut bytes = base32::decode(address).unwrap();
-        bytes.append(&mut port.to_le_bytes().to_vec());
-        bytes
-      }
+      PeerAddr::OnionV3(address, _) => base32::decode(address.to_lowercase()).unwrap(),
       #[cfg(feature = "i2p")]
-      PeerAddr::I2PB32(address, port) => {
-        let address = address.to_lowercase();
-        let mut bytes = base32::decode(address).unwrap();
-        bytes.append(&mut port.to_le_bytes().to_vec());
-        bytes
-      }
+      PeerAddr::I2PB32(address, _) => base32::decode(address.to_lowercase()).unwrap(),
       #[cfg(feature = "loki")]
-      PeerAddr::Loki(_address, _port) => {
+      PeerAddr::Loki(_address, _) => {
         unimplemented!()
         //   let address = address.to_lowercase();
-        //   let mut bytes = base32::decode(address).unwrap();
-        //   bytes.append(&mut port.to_le_bytes().to_vec());
-        //   bytes
+        //   base32::decode(address).unwrap()
       }
     }
   }
@@ -354,6 +558,7 @@ impl PeerAddr {
   /// let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
   /// assert_eq!(address.to_string(), "127.0.0.1:4321".to_string());
   /// ```
+  #[allow(clippy::inherent_to_string_shadow_display)]
   pub fn to_string(&self) -> String {
     match self {
       PeerAddr::IPV4(_, _) => {
@@ -374,16 +579,66 @@ impl PeerAddr {
       PeerAddr::Loki(address, port) => format!("{}.loki:{}", address, port),
     }
   }
-  pub fn get_pair(&self) -> Result<(Box<dyn Read + Send>, Box<dyn Write + Send>), AddressError> {
+  /// The URI scheme of the address' transport
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
+  /// assert_eq!(address.scheme(), "tcp");
+  /// ```
+  pub fn scheme(&self) -> &'static str {
+    match self {
+      PeerAddr::IPV4(_, _) | PeerAddr::IPV6(_, _) => "tcp",
+      #[cfg(feature = "tor")]
+      PeerAddr::OnionV2(_, _) | PeerAddr::OnionV3(_, _) => "onion",
+      #[cfg(feature = "i2p")]
+      PeerAddr::I2PB32(_, _) => "i2p",
+      #[cfg(feature = "loki")]
+      PeerAddr::Loki(_, _) => "loki",
+    }
+  }
+
+  /// The address as a URI, prefixed with its scheme
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
+  /// assert_eq!(address.to_uri(), "tcp://127.0.0.1:4321");
+  /// ```
+  pub fn to_uri(&self) -> String {
+    format!("{}://{}", self.scheme(), self.to_string())
+  }
+
+  /// The socket address for IP addresses, `None` for overlay addresses
+  /// ```
+  /// use std::net::SocketAddr;
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
+  /// let socket_addr: SocketAddr = "127.0.0.1:4321".parse().unwrap();
+  /// assert_eq!(address.as_socket_addr(), Some(socket_addr));
+  /// ```
+  pub fn as_socket_addr(&self) -> Option<SocketAddr> {
+    self.try_into().ok()
+  }
+  /// Dial an IP address with `TCP_NODELAY` set, since messages
+  /// are small and latency-sensitive. `std` cannot set keepalive,
+  /// use `check_idle` on the connection to detect dead peers.
+  pub fn connect_tcp(&self) -> Result<TcpStream, AddressError> {
     match self {
       PeerAddr::IPV4(_, _) | PeerAddr::IPV6(_, _) => {
         let socket = TcpStream::connect(self.to_string())?;
-        return Ok((Box::new(socket.try_clone()?), Box::new(socket)));
+        socket.set_nodelay(true)?;
+        Ok(socket)
       }
       #[cfg(any(feature = "i2p", feature = "tor"))]
       _ => Err(AddressError::TcpStreamError),
     }
   }
+  pub fn get_pair(&self) -> Result<StreamPair, AddressError> {
+    let socket = self.connect_tcp()?;
+    Ok((Box::new(socket.try_clone()?), Box::new(socket)))
+  }
 
   /// Change the port of the address.
   /// ```
@@ -407,6 +662,33 @@ impl PeerAddr {
       PeerAddr::Loki(addr, _) => PeerAddr::Loki(addr.to_string(), port),
     }
   }
+  /// An identity under which equivalent addresses compare equal,
+  /// for deduplicating peers. IPv4-mapped IPv6 addresses become
+  /// IPv4 and overlay addresses are lowercased. Onion v2 and v3
+  /// addresses of the same peer cannot be related to each other,
+  /// so they are left distinct.
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let ipv4 = PeerAddr::parse("1.2.3.4:15441").unwrap();
+  /// let mapped = PeerAddr::parse("[::ffff:1.2.3.4]:15441").unwrap();
+  /// assert_ne!(ipv4, mapped);
+  /// assert_eq!(ipv4.canonical_identity(), mapped.canonical_identity());
+  /// ```
+  pub fn canonical_identity(&self) -> PeerAddr {
+    match self {
+      PeerAddr::IPV6(_, _) => self.normalize(),
+      #[cfg(feature = "tor")]
+      PeerAddr::OnionV2(addr, port) => PeerAddr::OnionV2(addr.to_lowercase(), *port),
+      #[cfg(feature = "tor")]
+      PeerAddr::OnionV3(addr, port) => PeerAddr::OnionV3(addr.to_lowercase(), *port),
+      #[cfg(feature = "i2p")]
+      PeerAddr::I2PB32(addr, port) => PeerAddr::I2PB32(addr.to_lowercase(), *port),
+      #[cfg(feature = "loki")]
+      PeerAddr::Loki(addr, port) => PeerAddr::Loki(addr.to_lowercase(), *port),
+      _ => self.clone(),
+    }
+  }
   pub fn get_port(&self) -> u16 {
     match self {
       PeerAddr::IPV4(_, port) => *port,
@@ -421,6 +703,114 @@ impl PeerAddr {
       PeerAddr::Loki(_, port) => *port,
     }
   }
+  /// The address with most of its host masked, for logs that
+  /// should not expose peers. IP addresses keep their network
+  /// part, overlay addresses their first four characters.
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse("192.168.1.20:4321").unwrap();
+  /// assert_eq!(address.redacted(), "192.168.x.x:4321");
+  /// ```
+  pub fn redacted(&self) -> String {
+    match self {
+      PeerAddr::IPV4(ip, port) => format!("{}.{}.x.x:{}", ip[0], ip[1], port),
+      PeerAddr::IPV6(ip, port) => {
+        let ip = Ipv6Addr::from(*ip).segments();
+        format!("[{:x}:{:x}:x:x:x:x:x:x]:{}", ip[0], ip[1], port)
+      }
+      #[cfg(any(feature = "tor", feature = "i2p", feature = "loki"))]
+      _ => {
+        let address = self.to_string();
+        let (host, port) = address.rsplit_once(':').unwrap();
+        let (name, suffix) = host.split_once('.').unwrap();
+        let prefix: String = name.chars().take(4).collect();
+        format!("{}….{}:{}", prefix, suffix, port)
+      }
+    }
+  }
+  /// Collapse an IPv4-mapped IPv6 address (`::ffff:1.2.3.4`) into
+  /// its IPv4 form, which also packs to 6 bytes rather than 18.
+  /// `parse` keeps them as IPv6, see `canonical_identity`.
+  /// ```
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let mapped = PeerAddr::parse("[::ffff:1.2.3.4]:15441").unwrap();
+  /// assert_eq!(mapped.normalize(), PeerAddr::parse("1.2.3.4:15441").unwrap());
+  /// ```
+  pub fn normalize(&self) -> PeerAddr {
+    match self {
+      PeerAddr::IPV6(ip, port) => match Ipv6Addr::from(*ip).to_ipv4_mapped() {
+        Some(ipv4) => PeerAddr::IPV4(ipv4.octets(), *port),
+        None => self.clone(),
+      },
+      _ => self.clone(),
+    }
+  }
+  /// Whether the address falls in `pattern`, IPv4-mapped IPv6
+  /// addresses match IPv4 ranges.
+  pub fn matches(&self, pattern: &AddrPattern) -> bool {
+    let address = self.canonical_identity();
+    match (pattern, &address) {
+      (AddrPattern::Cidr(IpAddr::V4(network), prefix), PeerAddr::IPV4(ip, _)) => {
+        let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+        u32::from_be_bytes(*ip) & mask == u32::from(*network) & mask
+      }
+      (AddrPattern::Cidr(IpAddr::V6(network), prefix), PeerAddr::IPV6(ip, _)) => {
+        let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+        u128::from_be_bytes(*ip) & mask == u128::from(*network) & mask
+      }
+      (AddrPattern::Cidr(_, _), _) => false,
+      (AddrPattern::Host(host), address) => match address.to_string().rsplit_once(':') {
+        Some((address, _)) => address.eq_ignore_ascii_case(host),
+        None => false,
+      },
+    }
+  }
+  /// The transport family of the address
+  pub fn family(&self) -> AddrFamily {
+    match self {
+      PeerAddr::IPV4(_, _) => AddrFamily::IPV4,
+      PeerAddr::IPV6(_, _) => AddrFamily::IPV6,
+      #[cfg(feature = "tor")]
+      PeerAddr::OnionV2(_, _) | PeerAddr::OnionV3(_, _) => AddrFamily::Onion,
+      #[cfg(feature = "i2p")]
+      PeerAddr::I2PB32(_, _) => AddrFamily::I2P,
+      #[cfg(feature = "loki")]
+      PeerAddr::Loki(_, _) => AddrFamily::Loki,
+    }
+  }
+  /// Whether the address belongs to `family`
+  /// ```
+  /// use zeronet_protocol::address::AddrFamily;
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
+  /// assert!(address.is_family(AddrFamily::IPV4));
+  /// assert!(!address.is_family(AddrFamily::Onion));
+  /// ```
+  pub fn is_family(&self, family: AddrFamily) -> bool {
+    self.family() == family
+  }
+  /// Whether the address can be dialed with `config`: clearnet
+  /// always can, overlays need their transport enabled and a proxy.
+  /// ```
+  /// use zeronet_protocol::address::TransportConfig;
+  /// use zeronet_protocol::PeerAddr;
+  ///
+  /// let address = PeerAddr::parse("127.0.0.1:4321").unwrap();
+  /// assert!(address.is_routable(&TransportConfig::default()));
+  /// ```
+  pub fn is_routable(&self, config: &TransportConfig) -> bool {
+    let family = self.family();
+    if !supported_transports().contains(&family) {
+      return false;
+    }
+    match family {
+      AddrFamily::IPV4 | AddrFamily::IPV6 => true,
+      _ => config.proxy(family).is_some(),
+    }
+  }
   pub fn is_clearnet(&self) -> bool {
     match self {
       PeerAddr::IPV4(_, _) | PeerAddr::IPV6(_, _) => true,
@@ -430,24 +820,33 @@ impl PeerAddr {
   }
   #[cfg(feature = "tor")]
   pub fn is_onion(&self) -> bool {
-    match self {
-      PeerAddr::OnionV2(_, _) | PeerAddr::OnionV3(_, _) => true,
-      _ => false,
-    }
+    matches!(self, PeerAddr::OnionV2(_, _) | PeerAddr::OnionV3(_, _))
   }
   #[cfg(feature = "i2p")]
   pub fn is_i2p(&self) -> bool {
-    match self {
-      PeerAddr::I2PB32(_, _) => true,
-      _ => false,
-    }
+    matches!(self, PeerAddr::I2PB32(_, _))
   }
   #[cfg(feature = "loki")]
   pub fn is_loki(&self) -> bool {
-    match self {
-      PeerAddr::Loki(_, _) => true,
-      _ => false,
-    }
+    matches!(self, PeerAddr::Loki(_, _))
+  }
+}
+
+impl std::fmt::Display for PeerAddr {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let address_type = match self {
+      PeerAddr::IPV4(_, _) => "ipv4",
+      PeerAddr::IPV6(_, _) => "ipv6",
+      #[cfg(feature = "tor")]
+      PeerAddr::OnionV2(_, _) => "onionv2",
+      #[cfg(feature = "tor")]
+      PeerAddr::OnionV3(_, _) => "onionv3",
+      #[cfg(feature = "i2p")]
+      PeerAddr::I2PB32(_, _) => "i2pb32",
+      #[cfg(feature = "loki")]
+      PeerAddr::Loki(_, _) => "loki",
+    };
+    write!(f, "{} [{}]", address_type, self.to_string())
   }
 }
 
@@ -494,6 +893,361 @@ mod tests {
     assert_eq!(packed, [127, 0, 0, 1, 225, 16]);
   }
 
+  /// Guards the port byte order against ZeroNet's packing of
+  /// `1.2.3.4:15441` and `[::1]:258`, do not change the fixtures.
+  #[test]
+  fn test_port_packing_is_little_endian() {
+    let fixtures: [(&str, &[u8]); 2] = [
+      ("1.2.3.4:15441", &[1, 2, 3, 4, 0x51, 0x3c]),
+      (
+        "[::1]:258",
+        &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x02, 0x01],
+      ),
+    ];
+    for (address, bytes) in fixtures.iter() {
+      let address = PeerAddr::parse(*address).unwrap();
+      assert_eq!(address.pack(), bytes.to_vec());
+      assert_eq!(PeerAddr::unpack(bytes).unwrap(), address);
+    }
+  }
+
+  #[test]
+  fn test_ipv6_scope_is_dropped() {
+    use std::net::SocketAddrV6;
+
+    let ip = "fe80::1".parse().unwrap();
+    let scoped = SocketAddr::V6(SocketAddrV6::new(ip, 15441, 7, 3));
+    let address = PeerAddr::from(scoped);
+    let packed = address.pack();
+    assert_eq!(packed.len(), 18);
+
+    let unpacked = PeerAddr::unpack(&packed).unwrap();
+    let expected = SocketAddr::V6(SocketAddrV6::new(ip, 15441, 0, 0));
+    assert_eq!(unpacked.as_socket_addr(), Some(expected));
+  }
+
+  #[test]
+  fn test_host_bytes_ipv4() {
+    let address = PeerAddr::parse("127.0.0.1:4321").expect("could not parse address");
+
+    assert_eq!(address.host_bytes(), [127, 0, 0, 1]);
+    assert_eq!(address.with_port(1234).host_bytes(), [127, 0, 0, 1]);
+  }
+
+  #[test]
+  fn test_redacted() {
+    let address = PeerAddr::parse("192.168.1.20:4321").unwrap();
+    assert_eq!(address.redacted(), "192.168.x.x:4321");
+    let address = PeerAddr::parse("[2001:db8::ff00:42:8329]:4321").unwrap();
+    assert_eq!(address.redacted(), "[2001:db8:x:x:x:x:x:x]:4321");
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_redacted_onion() {
+    let address = PeerAddr::parse("zp2ynpztyxj2kw7x.onion:4321").unwrap();
+    assert_eq!(address.redacted(), "zp2y….onion:4321");
+    let address =
+      PeerAddr::parse("trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:4321")
+        .unwrap();
+    assert_eq!(address.redacted(), "trac….onion:4321");
+  }
+
+  #[test]
+  fn test_normalize() {
+    let address = PeerAddr::parse("[::ffff:127.0.0.1]:4321").unwrap();
+    assert_eq!(address.pack().len(), 18);
+    let address = address.normalize();
+    assert_eq!(address, PeerAddr::IPV4([127, 0, 0, 1], 4321));
+    assert_eq!(address.pack(), [127, 0, 0, 1, 225, 16]);
+
+    let ipv6 = PeerAddr::parse("[::1]:4321").unwrap();
+    assert_eq!(ipv6.normalize(), ipv6);
+  }
+
+  #[test]
+  fn test_canonical_identity() {
+    use std::collections::HashSet;
+
+    let addresses = [
+      "1.2.3.4:15441",
+      "[::ffff:1.2.3.4]:15441",
+      "1.2.3.4:15442",
+      "[::1]:15441",
+      "5.6.7.8:15441",
+    ];
+    let identities: HashSet<PeerAddr> = addresses
+      .iter()
+      .map(|address| PeerAddr::parse(*address).unwrap().canonical_identity())
+      .collect();
+
+    assert_eq!(identities.len(), 4);
+    assert!(identities.contains(&PeerAddr::parse("1.2.3.4:15441").unwrap()));
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_canonical_identity_onion() {
+    let v2 = PeerAddr::parse("zp2ynpztyxj2kw7x.onion:15441").unwrap();
+    let v2_upper = PeerAddr::parse("ZP2YNPZTYXJ2KW7X.onion:15441").unwrap();
+    let v3 =
+      PeerAddr::parse("pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion:15441")
+        .unwrap();
+
+    assert_eq!(v2.canonical_identity(), v2_upper.canonical_identity());
+    assert_ne!(v2.canonical_identity(), v3.canonical_identity());
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_is_routable_onion() {
+    let address = PeerAddr::parse("zp2ynpztyxj2kw7x.onion:15441").unwrap();
+    let mut config = TransportConfig::default();
+    assert!(!address.is_routable(&config));
+
+    config.tor_proxy = Some("127.0.0.1:9050".parse().unwrap());
+    assert!(address.is_routable(&config));
+    config.tor_proxy = None;
+    config.i2p_proxy = Some("127.0.0.1:4444".parse().unwrap());
+    assert!(!address.is_routable(&config));
+  }
+
+  #[test]
+  fn test_ip_constructors() {
+    let address = PeerAddr::ipv4(1, 2, 3, 4, 15441);
+    assert_eq!(address.to_string(), "1.2.3.4:15441");
+    let address = PeerAddr::ipv6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 15441);
+    assert_eq!(address.to_string(), "[2001:db8::1]:15441");
+    let address = PeerAddr::from_ip(IpAddr::V4(Ipv4Addr::LOCALHOST), 80);
+    assert_eq!(address.to_string(), "127.0.0.1:80");
+    let address = PeerAddr::from_ip(IpAddr::V6(Ipv6Addr::LOCALHOST), 80);
+    assert_eq!(address, PeerAddr::parse("[::1]:80").unwrap());
+  }
+
+  #[test]
+  fn test_as_socket_addr() {
+    let address = PeerAddr::parse("1.2.3.4:15441").unwrap();
+    let expected = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 15441);
+    assert_eq!(address.as_socket_addr(), Some(expected));
+
+    let address = PeerAddr::parse("[::1]:15441").unwrap();
+    let expected = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 15441);
+    assert_eq!(address.as_socket_addr(), Some(expected));
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_as_socket_addr_onion() {
+    let address = PeerAddr::parse("zp2ynpztyxj2kw7x.onion:15441").unwrap();
+    assert_eq!(address.as_socket_addr(), None);
+  }
+
+  #[test]
+  fn test_connect_tcp_nodelay() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = PeerAddr::from(listener.local_addr().unwrap());
+    let socket = address.connect_tcp().unwrap();
+    assert!(socket.nodelay().unwrap());
+  }
+
+  #[test]
+  fn test_scheme() {
+    let addresses = [
+      ("127.0.0.1:4321", "tcp", "tcp://127.0.0.1:4321"),
+      ("[::1]:4321", "tcp", "tcp://[::1]:4321"),
+      #[cfg(feature = "tor")]
+      (
+        "zp2ynpztyxj2kw7x.onion:4321",
+        "onion",
+        "onion://zp2ynpztyxj2kw7x.onion:4321",
+      ),
+      #[cfg(feature = "i2p")]
+      (
+        "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:4321",
+        "i2p",
+        "i2p://ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:4321",
+      ),
+      #[cfg(feature = "loki")]
+      ("abc.loki:4321", "loki", "loki://abc.loki:4321"),
+    ];
+    for (address, scheme, uri) in addresses.iter() {
+      let address = PeerAddr::parse(*address).unwrap();
+      assert_eq!(address.scheme(), *scheme);
+      assert_eq!(address.to_uri(), *uri);
+    }
+  }
+
+  #[test]
+  fn test_is_family() {
+    let families = [
+      AddrFamily::IPV4,
+      AddrFamily::IPV6,
+      AddrFamily::Onion,
+      AddrFamily::I2P,
+      AddrFamily::Loki,
+    ];
+    let addresses = [
+      ("127.0.0.1:4321", AddrFamily::IPV4),
+      ("[::1]:4321", AddrFamily::IPV6),
+      #[cfg(feature = "tor")]
+      ("zp2ynpztyxj2kw7x.onion:4321", AddrFamily::Onion),
+      #[cfg(feature = "i2p")]
+      (
+        "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p:4321",
+        AddrFamily::I2P,
+      ),
+      #[cfg(feature = "loki")]
+      ("abc.loki:4321", AddrFamily::Loki),
+    ];
+    for (address, expected) in addresses.iter() {
+      let address = PeerAddr::parse(*address).unwrap();
+      for family in families.iter() {
+        assert_eq!(address.is_family(*family), family == expected);
+      }
+    }
+  }
+
+  #[test]
+  fn test_matches() {
+    let cases = [
+      ("192.168.1.20:4321", "192.168.0.0/16", true),
+      ("192.169.0.1:4321", "192.168.0.0/16", false),
+      ("[::ffff:192.168.0.1]:4321", "192.168.0.0/16", true),
+      ("192.168.1.20:4321", "0.0.0.0/0", true),
+      ("10.0.0.1:1", "10.0.0.1", true),
+      ("10.0.0.2:1", "10.0.0.1", false),
+      ("[fe80::1]:4321", "fe80::/10", true),
+      ("[fec0::1]:4321", "fe80::/10", false),
+      ("192.168.1.20:4321", "fe80::/10", false),
+    ];
+    for (address, pattern, expected) in cases.iter() {
+      let address = PeerAddr::parse(*address).unwrap();
+      let pattern = AddrPattern::parse(pattern).unwrap();
+      assert_eq!(address.matches(&pattern), *expected);
+    }
+
+    assert!(AddrPattern::parse("192.168.0.0/33").is_err());
+    assert!(AddrPattern::parse("192.168.0.0/x").is_err());
+    assert!(AddrPattern::parse("127.0.0.1:4321").is_err());
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_matches_onion() {
+    let pattern = AddrPattern::parse("ZP2YNPZTYXJ2KW7X.onion").unwrap();
+    let address = PeerAddr::parse("zp2ynpztyxj2kw7x.onion:4321").unwrap();
+    assert!(address.matches(&pattern));
+    let other = PeerAddr::parse("ytcnzluhaxidtbf4.onion:4321").unwrap();
+    assert!(!other.matches(&pattern));
+    assert!(!address.matches(&AddrPattern::parse("0.0.0.0/0").unwrap()));
+  }
+
+  #[test]
+  fn test_parse_lenient() {
+    let addresses = [
+      ("127.0.0.1:4321", "127.0.0.1:4321"),
+      ("  127.0.0.1:4321\t", "127.0.0.1:4321"),
+      ("127.0.0.1:4321/", "127.0.0.1:4321"),
+      ("127.0.0.1:4321/index.html", "127.0.0.1:4321"),
+      ("\n[::1]:4321/\n", "[::1]:4321"),
+      #[cfg(feature = "tor")]
+      (
+        "zp2ynpztyxj2kw7x.onion:4321/ ",
+        "zp2ynpztyxj2kw7x.onion:4321",
+      ),
+    ];
+    for (dirty, clean) in addresses.iter() {
+      let address = PeerAddr::parse_lenient(dirty).unwrap();
+      assert_eq!(address, PeerAddr::parse(*clean).unwrap());
+    }
+
+    let invalid = [
+      "",
+      "/",
+      "127.0.0.1",
+      "127.0.0.1/:4321",
+      "127.0.0.1:99999/",
+      "not an address",
+    ];
+    for address in invalid.iter() {
+      assert!(PeerAddr::parse_lenient(address).is_err());
+    }
+  }
+
+  #[test]
+  fn test_parse_list() {
+    let text = "1.2.3.4:15441\n  [::1]:15442\n\nnot an address\n5.6.7.8:80\n";
+    let (peers, errors) = PeerAddr::parse_list(text);
+
+    let expected: Vec<PeerAddr> = ["1.2.3.4:15441", "[::1]:15442", "5.6.7.8:80"]
+      .iter()
+      .map(|peer| PeerAddr::parse(*peer).unwrap())
+      .collect();
+    assert_eq!(peers, expected);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, "not an address");
+
+    let (reparsed, errors) = PeerAddr::parse_list(&PeerAddr::to_list(&peers));
+    assert_eq!(reparsed, peers);
+    assert!(errors.is_empty());
+  }
+
+  #[test]
+  fn test_supported_transports() {
+    let transports = supported_transports();
+    assert!(transports.contains(&AddrFamily::IPV4));
+    assert!(transports.contains(&AddrFamily::IPV6));
+    assert_eq!(
+      transports.contains(&AddrFamily::Onion),
+      cfg!(feature = "tor")
+    );
+    assert_eq!(transports.contains(&AddrFamily::I2P), cfg!(feature = "i2p"));
+    assert_eq!(
+      transports.contains(&AddrFamily::Loki),
+      cfg!(feature = "loki")
+    );
+  }
+
+  #[test]
+  fn test_db_entry() {
+    let addresses = [
+      ("127.0.0.1:4321", 6),
+      ("[::1]:4321", 18),
+      #[cfg(feature = "tor")]
+      ("zp2ynpztyxj2kw7x.onion:4321", 12),
+      #[cfg(feature = "tor")]
+      (
+        "trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:4321",
+        37,
+      ),
+      #[cfg(feature = "i2p")]
+      (
+        "udhdrtrcetjm5sxzskjyr5ztpeszydbh4dpl3pl4utgqqw2v4jna.b32.i2p:4321",
+        34,
+      ),
+    ];
+    for (address, flag) in addresses.iter() {
+      let address = PeerAddr::parse(*address).unwrap();
+      let (entry_flag, bytes) = address.to_db_entry();
+      assert_eq!(entry_flag, *flag);
+      assert_eq!(bytes, address.pack());
+      assert_eq!(PeerAddr::from_db_entry(*flag, &bytes).unwrap(), address);
+    }
+  }
+
+  #[test]
+  fn test_db_entry_errors() {
+    let bytes = [127, 0, 0, 1, 225, 16];
+    assert!(matches!(
+      PeerAddr::from_db_entry(18, &bytes),
+      Err(AddressError::DbFlagMismatch(18))
+    ));
+    assert!(matches!(
+      PeerAddr::from_db_entry(5, &bytes[..5]),
+      Err(AddressError::InvalidBytearray(5))
+    ));
+  }
+
   #[test]
   fn test_pack_ipv6() {
     let address_string = "[1001:2002:3003:4004:5005:6006:7007:8008]:4321".to_string();
@@ -556,6 +1310,20 @@ mod tests {
     assert_eq!(unpacked.to_string(), address_string);
   }
 
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_parse_invalid_onionv3() {
+    // Not base32
+    let address = "trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrg01.onion:4321";
+    let result = PeerAddr::parse(address);
+    assert!(matches!(result, Err(ParseError::InvalidOnionV3(_))));
+
+    // Wrong version byte
+    let address = "trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgia.onion:4321";
+    let result = PeerAddr::parse(address);
+    assert!(matches!(result, Err(ParseError::InvalidOnionV3(_))));
+  }
+
   #[cfg(feature = "i2p")]
   #[test]
   fn test_pack_i2pb32() {
@@ -594,21 +1362,3 @@ mod tests {
     assert_eq!(unpacked.to_string(), address_string);
   }
 }
-
-impl std::fmt::Display for PeerAddr {
-  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-    let address_type = match self {
-      PeerAddr::IPV4(_, _) => "ipv4",
-      PeerAddr::IPV6(_, _) => "ipv6",
-      #[cfg(feature = "tor")]
-      PeerAddr::OnionV2(_, _) => "onionv2",
-      #[cfg(feature = "tor")]
-      PeerAddr::OnionV3(_, _) => "onionv3",
-      #[cfg(feature = "i2p")]
-      PeerAddr::I2PB32(_, _) => "i2pb32",
-      #[cfg(feature = "loki")]
-      PeerAddr::Loki(_, _) => "loki",
-    };
-    write!(f, "{} [{}]", address_type, self.to_string())
-  }
-}
@@ -1,33 +1,225 @@
 use crate::error::Error;
 use crate::requestable::Requestable;
+use crate::util::{
+  invalid_utf8_field, lossy_utf8, scan_frame, FrameScan, DEFAULT_MAX_ITEMS, DEFAULT_MAX_LEN,
+};
+use futures::future::join_all;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Which way bytes seen by a tap were going, see `Connection::set_tap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+  Outgoing,
+  Incoming,
+}
+
+/// A callback observing the raw bytes of a connection.
+pub type Tap = dyn Fn(Direction, &[u8]) + Send + Sync;
+
+/// Where the response to a pending request is stored until it is awaited.
+pub type ResponseSlot<T> = Arc<Mutex<Option<Result<T, Error>>>>;
+
+/// Runs the blocking reads and writes of a connection,
+/// see `Connection::set_spawner`.
+pub trait Spawner: Send + Sync {
+  fn spawn(&self, task: Box<dyn FnOnce() + Send>);
+}
+
+/// The default spawner, running every task on a new thread.
+pub struct ThreadSpawner;
+
+impl Spawner for ThreadSpawner {
+  fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+    std::thread::spawn(task);
+  }
+}
+
+/// Limits how many messages per second `recv` returns,
+/// allowing bursts of up to one second's worth.
+pub struct TokenBucket {
+  rate:   f64,
+  tokens: f64,
+  last:   Instant,
+}
+
+impl TokenBucket {
+  pub fn new(per_second: u32) -> TokenBucket {
+    TokenBucket {
+      rate:   per_second as f64,
+      tokens: per_second as f64,
+      last:   Instant::now(),
+    }
+  }
+
+  /// Take a token if one is available by `now`.
+  pub fn take(&mut self, now: Instant) -> bool {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+    self.last = now;
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+/// Caps how many of the connections sharing it may have a reader
+/// thread at once, see `Connection::set_reader_limit`. Connections
+/// over the cap wait for a reader of another one to finish.
+#[derive(Clone)]
+pub struct ReaderLimit(Arc<Mutex<ReaderLimitState>>);
+
+struct ReaderLimitState {
+  max:     usize,
+  running: usize,
+  // Wake the connections waiting for a reader thread
+  waiting: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+impl ReaderLimit {
+  pub fn new(max: usize) -> ReaderLimit {
+    let state = ReaderLimitState {
+      max,
+      running: 0,
+      waiting: vec![],
+    };
+    ReaderLimit(Arc::new(Mutex::new(state)))
+  }
+
+  /// How many reader threads are running.
+  pub fn running(&self) -> usize {
+    self.0.lock().unwrap().running
+  }
+
+  /// Take a slot, or queue `retry` to be called once one frees up.
+  fn acquire(&self, retry: Box<dyn FnOnce() + Send>) -> bool {
+    let mut state = self.0.lock().unwrap();
+    if state.running < state.max {
+      state.running += 1;
+      true
+    } else {
+      state.waiting.push(retry);
+      false
+    }
+  }
+
+  fn release(&self) {
+    let waiting = {
+      let mut state = self.0.lock().unwrap();
+      state.running -= 1;
+      std::mem::take(&mut state.waiting)
+    };
+    // Everyone retries, whoever is still reading takes the slot
+    for retry in waiting {
+      retry();
+    }
+  }
+}
 
 pub struct SharedState<T: Requestable> {
-  pub reader:   Arc<Mutex<dyn Read + Send>>,
-  pub writer:   Arc<Mutex<dyn Write + Send>>,
-  pub values:   Arc<Mutex<Vec<Result<T, Error>>>>,
+  pub reader:       Arc<Mutex<FrameReader>>,
+  pub writer:       Arc<Mutex<dyn Write + Send>>,
+  pub values:       Arc<Mutex<VecDeque<Result<T, Error>>>>,
   // Wakers for senders
-  pub requests: HashMap<T::Key, (Arc<Mutex<Option<Result<T, Error>>>>, Option<Waker>)>,
+  pub requests:     HashMap<T::Key, (ResponseSlot<T>, Option<Waker>)>,
   // Wakers for receivers
-  pub wakers:   Vec<Waker>,
-  pub closed:   bool,
+  pub wakers:       Vec<Waker>,
+  pub closed:       bool,
+  // Whether a thread is currently reading from `reader`
+  pub reading:      bool,
+  // Whether sends are written in the order they were made
+  pub ordered:      bool,
+  pub send_queue:   VecDeque<Arc<Mutex<SendState<T>>>>,
+  // How many unsent messages `send_queue` holds before dropping the oldest
+  pub send_limit:   Option<usize>,
+  // When a message was last written or read
+  pub last_active:  Instant,
+  // The error that closed the connection, if any
+  pub last_error:   Option<Arc<Error>>,
+  // How many requests may await their response at once
+  pub max_pending:  Option<usize>,
+  // Observes the raw bytes written and read
+  pub tap:          Option<Arc<Tap>>,
+  pub spawner:      Arc<dyn Spawner>,
+  // Limits the rate of received messages
+  pub rate_limit:   Option<TokenBucket>,
+  // Whether the connection closes once no requests are pending
+  pub quiescing:    bool,
+  // Caps the reader threads running across connections
+  pub reader_limit: Option<ReaderLimit>,
+  // The most items an array or map in a received message may hold
+  pub max_items:    usize,
+  // The most bytes any other value in a received message may hold
+  pub max_len:      usize,
 }
 
-pub struct SendState<T> {
-  pub writer: Arc<Mutex<dyn Write + Send>>,
-  pub value:  Option<T>,
-  pub result: Option<Result<(), Error>>,
+pub struct SendState<T: Requestable> {
+  pub writer:       Arc<Mutex<dyn Write + Send>>,
+  pub value:        Option<T>,
+  // Raw bytes written right after the message
+  pub buf:          Option<Vec<u8>>,
+  pub result:       Option<Result<(), Error>>,
+  // Used to close the connection when writing fails
+  pub shared_state: Arc<Mutex<SharedState<T>>>,
+  // Whether the message waits in the ordered send queue
+  pub queued:       bool,
+  pub waker:        Option<Waker>,
 }
 
-pub struct SendFuture<T> {
+impl<T: Requestable + Serialize> SendState<T> {
+  fn write(&mut self, writer: &mut dyn Write) {
+    // Batches are encoded up front and only carry `buf`
+    if self.result.is_none() && (self.value.is_some() || self.buf.is_some()) {
+      let tap = self.shared_state.lock().unwrap().tap.clone();
+      let mut result = match (self.value.take(), &tap) {
+        // Encoded up front so the tap sees exactly what was written
+        (Some(value), Some(tap)) => rmp_serde::to_vec_named(&value)
+          .map_err(Error::from)
+          .and_then(|bytes| {
+            writer.write_all(&bytes)?;
+            tap(Direction::Outgoing, &bytes);
+            Ok(())
+          }),
+        (Some(value), None) => {
+          rmp_serde::encode::write_named(&mut *writer, &value).map_err(Error::from)
+        }
+        (None, _) => Ok(()),
+      };
+      if let (Ok(()), Some(buf)) = (&result, self.buf.take()) {
+        result = writer
+          .write_all(&buf)
+          .and_then(|_| writer.flush())
+          .map_err(Error::from);
+        if let (Ok(()), Some(tap)) = (&result, &tap) {
+          tap(Direction::Outgoing, &buf);
+        }
+      }
+      let mut shared_state = self.shared_state.lock().unwrap();
+      if let Err(err) = &result {
+        // A failed write means the peer is gone,
+        // wake everyone waiting on this connection
+        fail_connection(&mut shared_state, copy_write_error(err));
+      } else {
+        shared_state.last_active = Instant::now();
+      }
+      self.result = Some(result);
+    }
+  }
+}
+
+pub struct SendFuture<T: Requestable> {
   pub state: Arc<Mutex<SendState<T>>>,
   pub waker: Option<Waker>,
 }
@@ -44,35 +236,63 @@ where
       return Poll::Ready(state.result.take().unwrap());
     }
 
+    let spawner = state.shared_state.lock().unwrap().spawner.clone();
+    if state.queued {
+      state.waker = Some(cx.waker().clone());
+      let writer = state.writer.clone();
+      let shared_state = state.shared_state.clone();
+      drop(state);
+      spawner.spawn(Box::new(move || write_queue(writer, shared_state)));
+      return Poll::Pending;
+    }
+    drop(state);
+
     let waker = cx.waker().clone();
     let moved_state = self.state.clone();
-    std::thread::spawn(move || {
+    spawner.spawn(Box::new(move || {
       let mut state = moved_state.lock().unwrap();
       let writer = state.writer.clone();
       let mut writer = writer.lock().unwrap();
 
       // TODO: add timeout for pending requests
 
-      if let Some(value) = state.value.take() {
-        let result = rmp_serde::encode::write_named(&mut *writer, &value)
-        	.map_err(|err| Error::from(err));
-        state.result = Some(result);
-      }
+      state.write(&mut *writer);
 
       waker.wake();
-    });
+    }));
 
     Poll::Pending
   }
 }
 
+/// Write every queued message in the order they were sent,
+/// holding the writer until the queue is empty.
+fn write_queue<T>(writer: Arc<Mutex<dyn Write + Send>>, shared_state: Arc<Mutex<SharedState<T>>>)
+where
+  T: 'static + DeserializeOwned + Serialize + Send + Requestable,
+{
+  let mut writer = writer.lock().unwrap();
+  loop {
+    let next = shared_state.lock().unwrap().send_queue.pop_front();
+    let state = match next {
+      Some(state) => state,
+      None => break,
+    };
+    let mut state = state.lock().unwrap();
+    state.write(&mut *writer);
+    if let Some(waker) = state.waker.take() {
+      waker.wake();
+    }
+  }
+}
+
 #[must_use = "futures do nothing unless polled"]
 pub struct ReceiveFuture<T>
 where
   T: 'static + DeserializeOwned + Serialize + Send + Requestable,
 {
   shared_state: Arc<Mutex<SharedState<T>>>,
-  values:       Arc<Mutex<Vec<Result<T, Error>>>>,
+  values:       Arc<Mutex<VecDeque<Result<T, Error>>>>,
 }
 
 impl<T> Future for ReceiveFuture<T>
@@ -83,19 +303,24 @@ where
 
   fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
     {
+      let mut shared_state = self.shared_state.lock().unwrap();
       let mut values = self.values.lock().unwrap();
-      if let Some(value) = values.pop() {
-        wake_one(&mut self.shared_state.lock().unwrap());
-        return Poll::Ready(value);
+      if !values.is_empty() {
+        // A limited message stays queued for a later `recv`
+        if let Some(bucket) = &mut shared_state.rate_limit {
+          if !bucket.take(Instant::now()) {
+            return Poll::Ready(Err(Error::RateLimited));
+          }
+        }
+        return Poll::Ready(values.pop_front().unwrap());
       }
+      if shared_state.closed {
+        return Poll::Ready(Err(Error::ConnectionClosed));
+      }
+      drop(values);
+      shared_state.wakers.push(cx.waker().clone());
     }
-
-    let waker = cx.waker().clone();
-    {
-      let mut shared_state = self.shared_state.lock().unwrap();
-      shared_state.wakers.push(waker.clone());
-    }
-    recv(self.shared_state.clone(), waker);
+    recv(self.shared_state.clone());
 
     Poll::Pending
   }
@@ -107,7 +332,7 @@ where
   T: 'static + DeserializeOwned + Serialize + Send + Requestable,
 {
   shared_state: Arc<Mutex<SharedState<T>>>,
-  value:        Arc<Mutex<Option<Result<T, Error>>>>,
+  value:        ResponseSlot<T>,
   req_id:       Option<T::Key>,
 }
 
@@ -119,114 +344,365 @@ where
 
   fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
     {
-      let mut value = self.value.lock().unwrap();
-      if let Some(value) = value.take() {
-        // Wake another future before returning
-        wake_one(&mut self.shared_state.lock().unwrap());
+      let mut shared_state = self.shared_state.lock().unwrap();
+      if let Some(value) = self.value.lock().unwrap().take() {
         return Poll::Ready(value);
       }
-    }
-
-    let waker = cx.waker().clone();
-    {
-      let mut shared_state = self.shared_state.lock().unwrap();
+      if shared_state.closed {
+        return Poll::Ready(Err(Error::ConnectionClosed));
+      }
       if let Some(req_id) = self.req_id.to_owned() {
         shared_state
           .requests
-          .insert(req_id, (self.value.clone(), Some(waker.clone())));
+          .insert(req_id, (self.value.clone(), Some(cx.waker().clone())));
       } else {
         return Poll::Ready(Err(Error::MissingReqId));
       }
     }
-    recv(self.shared_state.clone(), waker);
+    recv(self.shared_state.clone());
 
     Poll::Pending
   }
 }
 
-fn recv<T>(shared_state: Arc<Mutex<SharedState<T>>>, waker: Waker)
+impl<T> Drop for ResponseFuture<T>
 where
   T: 'static + DeserializeOwned + Serialize + Send + Requestable,
 {
-  let shared_state_g = shared_state.lock().unwrap();
+  /// A dropped future, e.g. after a timeout, stops waiting
+  /// so its request no longer counts as pending.
+  fn drop(&mut self) {
+    let req_id = match &self.req_id {
+      Some(req_id) => req_id,
+      None => return,
+    };
+    let mut shared_state = self.shared_state.lock().unwrap();
+    // The id may have been taken over by a later request
+    let ours = match shared_state.requests.get(req_id) {
+      Some((value, _)) => Arc::ptr_eq(value, &self.value),
+      None => false,
+    };
+    if ours {
+      shared_state.requests.remove(req_id);
+      if shared_state.quiescing && shared_state.requests.is_empty() {
+        close_connection(&mut shared_state);
+      }
+    }
+  }
+}
 
-  {
-    if shared_state_g.reader.try_lock().is_err() {
-      // We assume another receiver is already reading
-      // and we don't have to wake any other futures
+fn recv<T>(shared_state: Arc<Mutex<SharedState<T>>>)
+where
+  T: 'static + DeserializeOwned + Serialize + Send + Requestable,
+{
+  let mut shared_state_g = shared_state.lock().unwrap();
+  if shared_state_g.reading {
+    // Another receiver is already reading, it
+    // will wake us once it has read a message
+    return;
+  }
+  let reader_limit = shared_state_g.reader_limit.clone();
+  if let Some(limit) = &reader_limit {
+    let retry_state = shared_state.clone();
+    let retry = Box::new(move || wake_all(&mut retry_state.lock().unwrap()));
+    if !limit.acquire(retry) {
       return;
     }
   }
+  shared_state_g.reading = true;
 
   let reader = shared_state_g.reader.clone();
+  let tap = shared_state_g.tap.clone();
+  let max_items = shared_state_g.max_items;
+  let max_len = shared_state_g.max_len;
+  let spawner = shared_state_g.spawner.clone();
+  drop(shared_state_g);
   let moved_state = shared_state.clone();
-  std::thread::spawn(move || {
-    let mut reader = reader.lock().unwrap();
-    let response: Result<T, _> = rmp_serde::from_read(&mut *reader);
+  spawner.spawn(Box::new(move || {
+    let response: Result<T, (Error, Option<T::Key>)> = {
+      let mut reader = reader.lock().unwrap();
+      reader.read_message(tap.as_deref(), max_items, max_len)
+    };
     let mut moved_state = moved_state.lock().unwrap();
+    moved_state.reading = false;
 
-    if let Err(_) = response {
-      // TODO: do something with error
-      close_connection(&mut moved_state);
-      return;
+    match response {
+      // The message was read completely, only its content is
+      // invalid, so the connection can be used for the next one
+      Err((err @ Error::InvalidUtf8(_), to)) => {
+        let unclaimed = match to {
+          Some(to) => deliver(&mut moved_state, &to, Err(err)),
+          None => Some(Err(err)),
+        };
+        if let Some(err) = unclaimed {
+          moved_state.values.lock().unwrap().push_back(err);
+        }
+      }
+      // The peer closing the stream is not an error
+      Err((Error::ConnectionClosed, _)) => close_connection(&mut moved_state),
+      Err((err, _)) => fail_connection(&mut moved_state, err),
+      Ok(response) => {
+        moved_state.last_active = Instant::now();
+        match response.to() {
+          // Responses to requests we are not waiting for are dropped
+          Some(to) => {
+            deliver(&mut moved_state, &to, Ok(response));
+          }
+          None => {
+            let mut values = moved_state.values.lock().unwrap();
+            values.push_back(Ok(response));
+          }
+        }
+      }
+    }
+
+    // Every waiting future gets polled again, either to take
+    // its value or to start reading the next message
+    wake_all(&mut moved_state);
+    drop(moved_state);
+    if let Some(limit) = reader_limit {
+      limit.release();
     }
+  }));
+}
 
-    let response = response.unwrap();
+/// Hand `result` to the request awaiting the response `to`,
+/// or give it back if no such request is pending.
+fn deliver<T: Requestable>(
+  shared_state: &mut SharedState<T>,
+  to: &T::Key,
+  result: Result<T, Error>,
+) -> Option<Result<T, Error>> {
+  let Some((value, waker)) = shared_state.requests.remove(to) else {
+    return Some(result);
+  };
+  *value.lock().unwrap() = Some(result);
+  if let Some(waker) = waker {
+    waker.wake();
+  }
+  if shared_state.quiescing && shared_state.requests.is_empty() {
+    close_connection(shared_state);
+  }
+  None
+}
 
-    match response.to() {
-      Some(to) => {
-        let (value, other_waker) = moved_state.requests.remove(&to).unwrap();
-        let mut value = value.lock().unwrap();
-        *value = Some(Ok(response));
-        if let Some(other_waker) = other_waker {
-          other_waker.wake();
-        }
+/// Buffers what is read from the peer, so that bytes read past
+/// the end of one message are kept for the messages following it.
+pub struct FrameReader {
+  inner:  Box<dyn Read + Send>,
+  buffer: Vec<u8>,
+  // How far the message at the front of `buffer` was scanned
+  scan:   FrameScan,
+}
+
+impl FrameReader {
+  pub fn new(inner: Box<dyn Read + Send>) -> FrameReader {
+    FrameReader {
+      inner,
+      buffer: Vec::new(),
+      scan: FrameScan::default(),
+    }
+  }
+
+  /// Decodes a single message, followed by the raw bytes
+  /// it announces through `stream_bytes`, if any. When only the
+  /// content of the message is invalid, the error comes with the
+  /// `to` of the message so it can reach the request awaiting it.
+  fn read_message<T>(
+    &mut self,
+    tap: Option<&Tap>,
+    max_items: usize,
+    max_len: usize,
+  ) -> Result<T, (Error, Option<T::Key>)>
+  where
+    T: DeserializeOwned + Requestable,
+  {
+    let frame = self.read_frame(tap, max_items, max_len);
+    let frame = frame.map_err(|err| (err, None))?;
+    let decoded = rmp_serde::from_slice(&frame);
+    let mut message: T = decoded.map_err(|err| match invalid_utf8_field(&frame) {
+      Some(field) => {
+        // Decoded again with the invalid strings replaced, only to learn the `to`
+        let to = lossy_utf8(&frame)
+          .and_then(|bytes| rmp_serde::from_slice::<T>(&bytes).ok())
+          .and_then(|message| message.to());
+        (Error::InvalidUtf8(field), to)
       }
-      None => {
-        {
-          let mut values = moved_state.values.lock().unwrap();
-          values.push(Ok(response));
-        }
-        if let Some(other_waker) = moved_state.wakers.pop() {
-          other_waker.wake();
-        } else {
-          // No receivers to wake,
-          // wake up current future instead
-          waker.wake();
+      None => (Error::from(err), None),
+    })?;
+
+    if let Some(length) = message.stream_bytes() {
+      while self.buffer.len() < length {
+        self.fill().map_err(|err| (err, None))?;
+      }
+      let stream: Vec<u8> = self.buffer.drain(..length).collect();
+      if let Some(tap) = tap {
+        tap(Direction::Incoming, &stream);
+      }
+      message.set_stream(stream);
+    }
+
+    Ok(message)
+  }
+
+  /// The bytes of the next complete message.
+  fn read_frame(
+    &mut self,
+    tap: Option<&Tap>,
+    max_items: usize,
+    max_len: usize,
+  ) -> Result<Vec<u8>, Error> {
+    let length = loop {
+      if let Some(length) = scan_frame(&self.buffer, &mut self.scan, max_items, max_len)? {
+        break length;
+      }
+      self.fill()?;
+    };
+    let frame: Vec<u8> = self.buffer.drain(..length).collect();
+    self.scan = FrameScan::default();
+    if let Some(tap) = tap {
+      tap(Direction::Incoming, &frame);
+    }
+    Ok(frame)
+  }
+
+  /// Discard any buffered bytes.
+  pub fn clear(&mut self) {
+    self.buffer.clear();
+    self.scan = FrameScan::default();
+  }
+
+  /// Read whatever is available into the buffer,
+  /// reaching the end of the stream closes the connection.
+  fn fill(&mut self) -> Result<(), Error> {
+    let mut chunk = [0u8; 4096];
+    let length = RetryReader(&mut self.inner).read(&mut chunk)?;
+    if length == 0 {
+      return Err(Error::ConnectionClosed);
+    }
+    self.buffer.extend_from_slice(&chunk[..length]);
+    Ok(())
+  }
+}
+
+/// Retries reads that fail with `Interrupted` or `WouldBlock`,
+/// so that a non-blocking or partially filled stream does not
+/// abort decoding halfway through a message.
+struct RetryReader<'a, R: Read + ?Sized>(&'a mut R);
+
+impl<R: Read + ?Sized> Read for RetryReader<'_, R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+      match self.0.read(buf) {
+        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+          std::thread::sleep(Duration::from_millis(1));
         }
+        result => return result,
       }
     }
-  });
+  }
 }
 
-fn wake_one<T: Requestable>(shared_state: &mut SharedState<T>) {
-  if let Some(waker) = shared_state.wakers.pop() {
-    return waker.wake();
+fn wake_all<T: Requestable>(shared_state: &mut SharedState<T>) {
+  for waker in shared_state.wakers.drain(..) {
+    waker.wake();
   }
-  if let Some((_, Some(waker))) = shared_state.requests.values().next() {
-    return waker.clone().wake();
+  for (_, waker) in shared_state.requests.values_mut() {
+    if let Some(waker) = waker.take() {
+      waker.wake();
+    }
+  }
+}
+
+/// A copy of an error from writing, for `last_error`, the I/O error
+/// underneath encoding errors is kept as `Error::Io`.
+fn copy_write_error(err: &Error) -> Error {
+  use rmp::encode::ValueWriteError;
+  use rmp_serde::encode::Error as EncodeError;
+
+  let io = match err {
+    Error::Io(err) => err,
+    Error::EncodeRMPError(EncodeError::InvalidValueWrite(
+      ValueWriteError::InvalidMarkerWrite(err) | ValueWriteError::InvalidDataWrite(err),
+    )) => err,
+    err => return Error::Other(err.to_string()),
+  };
+  Error::Io(std::io::Error::new(io.kind(), io.to_string()))
+}
+
+/// Close the connection, keeping `error` as the reason
+/// unless it was already closed.
+fn fail_connection<T: Requestable>(shared_state: &mut SharedState<T>, error: Error) {
+  if !shared_state.closed {
+    shared_state.last_error = Some(Arc::new(error));
   }
+  close_connection(shared_state);
 }
 
 fn close_connection<T: Requestable>(shared_state: &mut SharedState<T>) {
   shared_state.closed = true;
 
-  for (value, waker) in shared_state.requests.values() {
+  fail_requests(shared_state);
+  let mut values = shared_state.values.lock().unwrap();
+  while let Some(waker) = shared_state.wakers.pop() {
+    values.push_back(Err(Error::ConnectionClosed));
+    waker.wake();
+  }
+}
+
+/// Start waiting for the response to `message` in `value`,
+/// or give the error the request is rejected with.
+fn register_request<T: Requestable>(
+  shared_state: &mut SharedState<T>,
+  message: &T,
+  value: &ResponseSlot<T>,
+) -> Option<Error> {
+  let full = match shared_state.max_pending {
+    Some(max_pending) => shared_state.requests.len() >= max_pending,
+    None => false,
+  };
+  match message.req_id() {
+    _ if shared_state.quiescing => Some(Error::ShuttingDown),
+    _ if full => Some(Error::TooManyRequests),
+    // Replacing the waiter would strand the earlier request
+    Some(req_id) if shared_state.requests.contains_key(&req_id) => Some(Error::DuplicateReqId),
+    Some(req_id) => {
+      shared_state.requests.insert(req_id, (value.clone(), None));
+      None
+    }
+    None => None,
+  }
+}
+
+/// Fail every pending request with `ConnectionClosed`,
+/// no response will reach them anymore.
+fn fail_requests<T: Requestable>(shared_state: &mut SharedState<T>) {
+  for (_, (value, waker)) in shared_state.requests.drain() {
     let mut value = value.lock().unwrap();
-    match *value {
-      None => {
-        *value = Some(Err(Error::ConnectionClosed));
-      }
-      Some(_) => {}
+    if value.is_none() {
+      *value = Some(Err(Error::ConnectionClosed));
     }
-    if let Some(waker) = waker.clone() {
+    if let Some(waker) = waker {
       waker.wake();
     }
   }
-  let mut values = shared_state.values.lock().unwrap();
-  while let Some(waker) = shared_state.wakers.pop() {
-    values.push(Err(Error::ConnectionClosed));
-    waker.wake();
+}
+
+/// Shared by all clones of a connection, but not by its futures,
+/// so it is dropped together with the last clone.
+struct DropHandle<T: Requestable> {
+  shared_state:  Arc<Mutex<SharedState<T>>>,
+  close_on_drop: AtomicBool,
+}
+
+impl<T: Requestable> Drop for DropHandle<T> {
+  fn drop(&mut self) {
+    if self.close_on_drop.load(Ordering::SeqCst) {
+      if let Ok(mut shared_state) = self.shared_state.lock() {
+        close_connection(&mut shared_state);
+      }
+    }
   }
 }
 
@@ -236,6 +712,7 @@ where
   T: 'static + DeserializeOwned + Serialize + Send + Requestable,
 {
   pub shared_state: Arc<Mutex<SharedState<T>>>,
+  handle:           Arc<DropHandle<T>>,
 }
 
 impl<T> Connection<T>
@@ -244,33 +721,289 @@ where
 {
   pub fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Self {
     let shared_state = SharedState::<T> {
-      reader:   Arc::new(Mutex::new(reader)),
-      writer:   Arc::new(Mutex::new(writer)),
-      requests: HashMap::new(),
-      values:   Arc::new(Mutex::new(vec![])),
-      wakers:   vec![],
-      closed:   false,
+      reader:       Arc::new(Mutex::new(FrameReader::new(reader))),
+      writer:       Arc::new(Mutex::new(writer)),
+      requests:     HashMap::new(),
+      values:       Arc::new(Mutex::new(VecDeque::new())),
+      wakers:       vec![],
+      closed:       false,
+      reading:      false,
+      ordered:      false,
+      send_queue:   VecDeque::new(),
+      send_limit:   None,
+      last_active:  Instant::now(),
+      last_error:   None,
+      max_pending:  None,
+      tap:          None,
+      spawner:      Arc::new(ThreadSpawner),
+      rate_limit:   None,
+      quiescing:    false,
+      reader_limit: None,
+      max_items:    DEFAULT_MAX_ITEMS,
+      max_len:      DEFAULT_MAX_LEN,
     };
-    return Self {
-      shared_state: Arc::new(Mutex::new(shared_state)),
+    let shared_state = Arc::new(Mutex::new(shared_state));
+    let handle = DropHandle {
+      shared_state:  shared_state.clone(),
+      close_on_drop: AtomicBool::new(false),
     };
+    Self {
+      shared_state,
+      handle: Arc::new(handle),
+    }
+  }
+  /// When enabled, dropping the last clone of the connection closes
+  /// it, pending futures then resolve to `ConnectionClosed`.
+  pub fn set_close_on_drop(&self, close_on_drop: bool) {
+    self.handle.close_on_drop.store(close_on_drop, Ordering::SeqCst);
   }
   pub fn is_closed(&self) -> bool {
     let shared_state = self.shared_state.lock().unwrap();
-    return shared_state.closed;
+    shared_state.closed
+  }
+  /// The error that closed the connection, `None` while it is open
+  /// or when it was closed cleanly, e.g. by the peer or `check_idle`.
+  /// Write errors are kept as a copy, the original goes to the sender.
+  pub fn last_error(&self) -> Option<Arc<Error>> {
+    self.shared_state.lock().unwrap().last_error.clone()
+  }
+  /// When a message was last written to or read from the connection,
+  /// or when it was created if neither happened yet.
+  pub fn last_active(&self) -> Instant {
+    self.shared_state.lock().unwrap().last_active
+  }
+  /// Close the connection if nothing was written or read for
+  /// longer than `max_idle`, returns whether it was idle.
+  pub fn check_idle(&self, max_idle: Duration) -> bool {
+    self.check_idle_at(Instant::now(), max_idle)
+  }
+  /// Like `check_idle`, but measured up to `now` instead of the
+  /// current time, so callers can drive their own clock.
+  pub fn check_idle_at(&self, now: Instant, max_idle: Duration) -> bool {
+    let mut shared_state = self.shared_state.lock().unwrap();
+    let idle = now.saturating_duration_since(shared_state.last_active) > max_idle;
+    if idle {
+      close_connection(&mut shared_state);
+    }
+    idle
   }
 
   pub fn send(&mut self, message: T) -> impl Future<Output = Result<(), Error>> {
-    let shared_state = self.shared_state.lock().unwrap();
+    self.send_buf(Some(message), None)
+  }
+
+  /// Send a message followed by raw bytes, which the
+  /// message should announce, e.g. through `stream_bytes`.
+  pub fn send_with_buf(
+    &mut self,
+    message: T,
+    buf: Vec<u8>,
+  ) -> impl Future<Output = Result<(), Error>> {
+    self.send_buf(Some(message), Some(buf))
+  }
+
+  /// Encode all `messages` and write them with a single write
+  /// and flush, the peer reads them as separate messages in order.
+  /// A tap sees the whole batch at once. Responses to requests in
+  /// the batch are not waited for and get dropped, use
+  /// `request_batch` to receive them.
+  pub fn send_batch(&mut self, messages: Vec<T>) -> impl Future<Output = Result<(), Error>> {
+    let mut bytes = vec![];
+    let encoded = messages
+      .iter()
+      .try_for_each(|message| rmp_serde::encode::write_named(&mut bytes, message));
+    let send_future = match encoded {
+      Ok(()) => Ok(self.send_buf(None, Some(bytes))),
+      Err(err) => Err(Error::from(err)),
+    };
+
+    async {
+      let send_future = send_future?;
+      send_future.await
+    }
+  }
+
+  /// Like `send_batch`, but every message is a request awaiting its
+  /// response, resolving to those in order of the requests once all
+  /// of them arrived. Nothing is sent if any request is rejected,
+  /// e.g. with `TooManyRequests`.
+  pub fn request_batch(
+    &mut self,
+    messages: Vec<T>,
+  ) -> impl Future<Output = Result<Vec<Result<T, Error>>, Error>> {
+    let mut futures = vec![];
+    let rejected = {
+      let mut shared_state = self.shared_state.lock().unwrap();
+      messages.iter().find_map(|message| {
+        let value = Arc::new(Mutex::new(None));
+        let rejected = register_request(&mut shared_state, message, &value);
+        futures.push(ResponseFuture {
+          shared_state: self.shared_state.clone(),
+          value,
+          req_id:       message.req_id(),
+        });
+        rejected
+      })
+    };
+
+    // Dropping the futures of a rejected batch unregisters them
+    let send_future = match rejected {
+      Some(err) => Err(err),
+      None => Ok(self.send_batch(messages)),
+    };
+
+    async {
+      send_future?.await?;
+      Ok(join_all(futures).await)
+    }
+  }
+
+  fn send_buf(&mut self, message: Option<T>, buf: Option<Vec<u8>>) -> SendFuture<T> {
+    let mut shared_state = self.shared_state.lock().unwrap();
+    // A send limit needs the queue to drop from
+    let queued = shared_state.ordered || shared_state.send_limit.is_some();
     let state = SendState {
-      writer: shared_state.writer.clone(),
-      result: None,
-      value:  Some(message),
+      writer:       shared_state.writer.clone(),
+      result:       None,
+      value:        message,
+      buf,
+      shared_state: self.shared_state.clone(),
+      queued,
+      waker:        None,
     };
-    SendFuture {
-      state: Arc::new(Mutex::new(state)),
-      waker: None,
+    let state = Arc::new(Mutex::new(state));
+    let mut dropped = vec![];
+    if queued {
+      if let Some(limit) = shared_state.send_limit {
+        while shared_state.send_queue.len() >= limit {
+          match shared_state.send_queue.pop_front() {
+            Some(oldest) => dropped.push(oldest),
+            None => break,
+          }
+        }
+      }
+      shared_state.send_queue.push_back(state.clone());
     }
+    drop(shared_state);
+
+    // Locked only after the connection, a polled future
+    // takes its own lock before the connection's
+    for dropped in dropped {
+      let mut dropped = dropped.lock().unwrap();
+      dropped.result = Some(Err(Error::SendDropped));
+      let req_id = dropped.value.take().and_then(|value| value.req_id());
+      if let Some(req_id) = req_id {
+        self.shared_state.lock().unwrap().requests.remove(&req_id);
+      }
+      if let Some(waker) = dropped.waker.take() {
+        waker.wake();
+      }
+    }
+    SendFuture { state, waker: None }
+  }
+
+  /// If a decode error closed the connection, discard everything
+  /// buffered by the reader and reopen it, so reading can resume
+  /// with the next bytes the peer sends. Connections closed for
+  /// any other reason stay closed. Fails with `ReadInProgress`
+  /// rather than waiting for a read to finish. Requests still
+  /// pending fail with `ConnectionClosed`, responses that arrive
+  /// after the reset are not matched to them.
+  pub fn reset_reader(&mut self) -> Result<(), Error> {
+    let mut shared_state = self.shared_state.lock().unwrap();
+    if shared_state.reading {
+      return Err(Error::ReadInProgress);
+    }
+    let decode_error = matches!(
+      shared_state.last_error.as_deref(),
+      Some(Error::InvalidMessagePack(_)) | Some(Error::MessageTooLarge)
+    );
+    if decode_error {
+      // Nobody else reads while `reading` is unset
+      shared_state.reader.lock().unwrap().clear();
+      fail_requests(&mut shared_state);
+      shared_state.closed = false;
+      shared_state.last_error = None;
+    }
+    Ok(())
+  }
+
+  /// When enabled, messages are written in the order `send`
+  /// and `request` were called rather than the order their
+  /// futures are polled in.
+  pub fn set_ordered_sends(&self, ordered: bool) {
+    self.shared_state.lock().unwrap().ordered = ordered;
+  }
+
+  /// Hold at most `limit` unsent messages. Sending to a full queue
+  /// drops its oldest message, whose send fails with `SendDropped`,
+  /// so a slow peer sheds stale messages instead of buffering them.
+  /// While a limit is set, sends are queued and written in order
+  /// as with `set_ordered_sends(true)`. `None`, the default, never
+  /// drops and leaves ordering to `set_ordered_sends`.
+  pub fn set_send_limit(&self, limit: Option<usize>) {
+    self.shared_state.lock().unwrap().send_limit = limit;
+  }
+
+  /// Call `tap` with the raw bytes of every message written or read
+  /// from now on, including streamed bodies, in the order they pass.
+  pub fn set_tap(&mut self, tap: impl Fn(Direction, &[u8]) + Send + Sync + 'static) {
+    self.shared_state.lock().unwrap().tap = Some(Arc::new(tap));
+  }
+
+  /// Run the connection's blocking reads and writes with `spawner`
+  /// instead of a new thread each, e.g. on a thread pool.
+  pub fn set_spawner(&self, spawner: impl Spawner + 'static) {
+    self.shared_state.lock().unwrap().spawner = Arc::new(spawner);
+  }
+
+  /// Stop accepting new requests, which fail with `ShuttingDown`,
+  /// and close the connection once the pending ones are answered.
+  pub fn quiesce(&mut self) {
+    let mut shared_state = self.shared_state.lock().unwrap();
+    shared_state.quiescing = true;
+    if shared_state.requests.is_empty() {
+      close_connection(&mut shared_state);
+    }
+  }
+
+  /// How many requests are awaiting their response.
+  pub fn pending_request_count(&self) -> usize {
+    self.shared_state.lock().unwrap().requests.len()
+  }
+
+  /// Share `limit` with other connections to cap how many of them
+  /// read at once, `None`, the default, reads whenever polled.
+  pub fn set_reader_limit(&self, limit: Option<ReaderLimit>) {
+    self.shared_state.lock().unwrap().reader_limit = limit;
+  }
+
+  /// Limit how many items an array or map in a received message
+  /// may declare, `DEFAULT_MAX_ITEMS` by default. A peer exceeding
+  /// it fails the connection with `MessageTooLarge`.
+  pub fn set_max_items(&self, max_items: usize) {
+    self.shared_state.lock().unwrap().max_items = max_items;
+  }
+
+  /// Limit how many bytes a string, binary or extension value in a
+  /// received message may declare, `DEFAULT_MAX_LEN` by default.
+  /// A peer exceeding it fails the connection with `MessageTooLarge`.
+  pub fn set_max_len(&self, max_len: usize) {
+    self.shared_state.lock().unwrap().max_len = max_len;
+  }
+
+  /// Limit `recv` to `per_second` messages, with bursts of up to
+  /// as many. Past the limit `recv` fails with `RateLimited` and
+  /// the message stays queued until the limit recovers.
+  pub fn set_rate_limit(&self, per_second: Option<u32>) {
+    self.shared_state.lock().unwrap().rate_limit = per_second.map(TokenBucket::new);
+  }
+
+  /// Limit how many requests may await their response at once,
+  /// further requests fail with `TooManyRequests` without being
+  /// sent. `None`, the default, allows any number.
+  pub fn set_max_pending(&self, max_pending: Option<usize>) {
+    self.shared_state.lock().unwrap().max_pending = max_pending;
   }
 
   pub fn recv(&mut self) -> impl Future<Output = Result<T, Error>> {
@@ -283,30 +1016,46 @@ where
   }
 
   pub fn request(&mut self, message: T) -> impl Future<Output = Result<T, Error>> {
+    self.request_buf(message, None)
+  }
+
+  /// Like `request`, but writes `buf` right after the message,
+  /// see `send_with_buf`.
+  pub fn request_with_buf(
+    &mut self,
+    message: T,
+    buf: Vec<u8>,
+  ) -> impl Future<Output = Result<T, Error>> {
+    self.request_buf(message, Some(buf))
+  }
+
+  fn request_buf(
+    &mut self,
+    message: T,
+    buf: Option<Vec<u8>>,
+  ) -> impl Future<Output = Result<T, Error>> {
     let value = Arc::new(Mutex::new(None));
 
-    {
+    let rejected = {
       let mut shared_state = self.shared_state.lock().unwrap();
-      if let Some(req_id) = message.req_id() {
-        shared_state.requests.insert(req_id, (value.clone(), None));
-      }
-    }
+      register_request(&mut shared_state, &message, &value)
+    };
 
     let future = ResponseFuture {
       shared_state: self.shared_state.clone(),
-      value:        value,
+      value,
       req_id:       message.req_id(),
     };
 
-    let send_future = self.send(message);
-
-    return async {
-      let res = send_future.await;
-      if res.is_ok() {
-        future.await
-      } else {
-        Err(res.unwrap_err())
-      }
+    // A rejected request is never sent
+    let send_future = match rejected {
+      Some(err) => Err(err),
+      None => Ok(self.send_buf(Some(message), buf)),
     };
+
+    async {
+      send_future?.await?;
+      future.await
+    }
   }
 }
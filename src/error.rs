@@ -33,6 +33,32 @@ pub enum Error {
   UnexpectedRequest,
   #[error("Missing request id")]
   MissingReqId,
+  #[error("Response is missing the streamed body")]
+  MissingStream,
+  #[error("Invalid UTF-8 in string field `{0}`")]
+  InvalidUtf8(String),
+  #[error("Too many requests are awaiting a response")]
+  TooManyRequests,
+  #[error("A request with the same id is already awaiting a response")]
+  DuplicateReqId,
+  #[error("Invalid handshake: {0}")]
+  InvalidHandshake(String),
+  #[error("The connection is shutting down")]
+  ShuttingDown,
+  #[error("No response was produced before the deadline")]
+  DeadlineExceeded,
+  #[error("Messages are arriving faster than the rate limit")]
+  RateLimited,
+  #[error("Signature does not match the message")]
+  InvalidSignature,
+  #[error("Message declares more array or map items, or longer values, than allowed")]
+  MessageTooLarge,
+  #[error("Message was dropped from the full send queue")]
+  SendDropped,
+  #[error("A read is in progress")]
+  ReadInProgress,
+  #[error("Hash is {0} bytes long instead of 32")]
+  InvalidHashLength(usize),
 
   #[error("This shouldn't even exist")]
   Other(String),
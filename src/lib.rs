@@ -3,11 +3,14 @@ pub mod async_connection;
 pub mod error;
 pub mod message;
 pub mod requestable;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod util;
 pub mod zero_connection;
 
-pub use address::{PeerAddr, ToPeerAddrs};
+pub use address::{supported_transports, PeerAddr, ToPeerAddrs};
 pub use error::Error;
 pub use message::templates;
-pub use message::ZeroMessage;
+pub use message::templates::DEFAULT_PEER_ID_PREFIX;
+pub use message::{known_commands, ZeroMessage};
 pub use zero_connection::ZeroConnection;
@@ -1,46 +1,422 @@
 use crate::error::Error;
 use crate::requestable::Requestable;
-use crate::util::is_default;
+use crate::util::{frame_len, is_default, with_defaults};
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
 
+pub mod signing;
 pub mod templates;
 pub mod value;
 
 use value::Value;
 
+/// A response to a request, matched to it by `to`.
+///
+/// Responses are normally sent named, with the body flattened
+/// into the message map. Some older peers send them positionally
+/// as `[cmd, to, body]` instead, which is accepted when decoding
+/// for every command. No command in `templates` requires it, and
+/// responses are always encoded named. Requests are never decoded
+/// positionally, since `[cmd, req_id, params]` would be ambiguous.
+/// A named response without `cmd` decodes as `"response"`.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "ResponseRepr")]
 pub struct Response {
   pub cmd:  String,
   pub to:   usize,
   #[serde(flatten)]
   response: Value,
+  /// Raw bytes that followed the response on the wire,
+  /// as announced by its `stream_bytes` field.
+  #[serde(skip)]
+  stream:   Option<ByteBuf>,
+}
+
+/// The named and positional forms a response may be decoded from.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ResponseRepr {
+  Named {
+    // Some peers leave it out and rely on `to` alone
+    #[serde(default = "response_cmd")]
+    cmd:      String,
+    to:       usize,
+    #[serde(flatten)]
+    response: Value,
+  },
+  Positional(String, usize, Value),
+}
+
+fn response_cmd() -> String {
+  "response".to_string()
+}
+
+impl From<ResponseRepr> for Response {
+  fn from(repr: ResponseRepr) -> Response {
+    let (cmd, to, response) = match repr {
+      ResponseRepr::Named { cmd, to, response } => (cmd, to, response),
+      ResponseRepr::Positional(cmd, to, response) => (cmd, to, response),
+    };
+    Response {
+      cmd,
+      to,
+      response,
+      stream: None,
+    }
+  }
 }
 
 impl Response {
+  /// Decode the response into a template. Fields the template
+  /// does not know about are ignored rather than rejected, so
+  /// peers may add fields without breaking older clients.
+  /// The response itself keeps them, see `raw`.
   pub fn body<V: DeserializeOwned + Serialize>(&self) -> Result<V, Error> {
     let result = serde_json::to_value(&self.response)?;
-    let result = serde_json::from_value(result)?;
-    Ok(result)
+    serde_json::from_value(result)
+      .map_err(|err| invalid_utf8_error::<V>(&self.response, "", err.into()))
+  }
+
+  /// Every field of the response besides `cmd` and `to`, as
+  /// received, for forwarding it without losing unknown fields.
+  pub fn raw(&self) -> &Value {
+    &self.response
+  }
+
+  /// The number of raw bytes following this response,
+  /// taken from its `stream_bytes` field.
+  pub fn stream_bytes(&self) -> Option<usize> {
+    get_stream_bytes(&self.response)
+  }
+
+  /// Announce `length` raw bytes following this response
+  /// by setting its `stream_bytes` field.
+  pub fn set_stream_bytes(&mut self, length: usize) {
+    set_stream_bytes(&mut self.response, length)
+  }
+
+  /// The raw bytes that followed this response, if any.
+  pub fn stream(&self) -> Option<&[u8]> {
+    self.stream.as_deref().map(|stream| stream.as_slice())
+  }
+
+  /// Take the raw bytes that followed this response, if any.
+  pub fn take_stream(&mut self) -> Option<Vec<u8>> {
+    self.stream.take().map(ByteBuf::into_vec)
   }
 }
 
+/// Constructors for test fixtures, with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+impl Response {
+  pub fn new(to: usize, body: Value) -> Response {
+    Response {
+      cmd: "response".to_string(),
+      to,
+      response: body,
+      stream: None,
+    }
+  }
+}
+
+/// The `req_id` of a notification, which is sent without one.
+pub const NOTIFICATION_REQ_ID: usize = usize::MAX;
+
+fn notification_req_id() -> usize {
+  NOTIFICATION_REQ_ID
+}
+
+fn is_notification_req_id(req_id: &usize) -> bool {
+  *req_id == NOTIFICATION_REQ_ID
+}
+
+/// A request, or a notification when it has no `req_id`,
+/// which must not be responded to.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Request {
   pub cmd:    String,
+  #[serde(
+    default = "notification_req_id",
+    skip_serializing_if = "is_notification_req_id"
+  )]
   pub req_id: usize,
   #[serde(default, skip_serializing_if = "is_default")]
   params:     Value,
+  /// Raw bytes that followed the request on the wire,
+  /// as announced by the `stream_bytes` field of its params.
+  #[serde(skip)]
+  stream:     Option<ByteBuf>,
 }
 
+/// Constructors for test fixtures, with the `testing` feature.
+///
+/// # Examples
+///
+/// Only compiled with the `testing` feature, so this example runs
+/// under `cargo test --features testing`, as CI does.
+/// ```
+/// use zeronet_protocol::message::{value::Value, Request};
+///
+/// let request = Request::new("ping", 3, Value::Null);
+/// assert_eq!(request.req_id, 3);
+/// assert_eq!(request.cmd, "ping");
+/// ```
+#[cfg(any(test, feature = "testing"))]
 impl Request {
+  pub fn new(cmd: &str, req_id: usize, params: Value) -> Request {
+    Request {
+      cmd: cmd.to_string(),
+      req_id,
+      params,
+      stream: None,
+    }
+  }
+}
+
+impl Request {
+  /// Whether the peer expects no response, see `ZeroMessage::notification`.
+  pub fn is_notification(&self) -> bool {
+    self.req_id == NOTIFICATION_REQ_ID
+  }
+
+  /// The `cmd` of the request with known aliases normalized.
+  pub fn command(&self) -> Command {
+    Command::from(self.cmd.as_str())
+  }
+
+  /// Decode the params into a template. Absent params are
+  /// sent by some peers instead of `"params": {}`, they decode
+  /// like an empty map unless the template accepts `null`.
   pub fn body<V: DeserializeOwned + Serialize>(&self) -> Result<V, Error> {
     let result = serde_json::to_value(&self.params)?;
-    let result = serde_json::from_value(result)?;
-    Ok(result)
+    match serde_json::from_value(result) {
+      Err(_) if self.params == Value::Null => {
+        let empty = serde_json::Value::Object(serde_json::Map::new());
+        Ok(serde_json::from_value(empty)?)
+      }
+      result => result.map_err(|err| invalid_utf8_error::<V>(&self.params, "params", err.into())),
+    }
+  }
+
+  /// The number of raw bytes following this request,
+  /// taken from the `stream_bytes` field of its params.
+  pub fn stream_bytes(&self) -> Option<usize> {
+    get_stream_bytes(&self.params)
+  }
+
+  /// Announce `length` raw bytes following this request
+  /// by setting the `stream_bytes` field of its params.
+  pub fn set_stream_bytes(&mut self, length: usize) {
+    set_stream_bytes(&mut self.params, length)
+  }
+
+  /// The raw bytes that followed this request, if any.
+  pub fn stream(&self) -> Option<&[u8]> {
+    self.stream.as_deref().map(|stream| stream.as_slice())
+  }
+
+  /// Take the raw bytes that followed this request, if any.
+  pub fn take_stream(&mut self) -> Option<Vec<u8>> {
+    self.stream.take().map(ByteBuf::into_vec)
+  }
+}
+
+/// `Error::InvalidUtf8` instead of `err` if the body failed to
+/// decode because of a string that is not valid UTF-8, which is
+/// kept as `Bytes` since it may as well have been binary data.
+fn invalid_utf8_error<V: DeserializeOwned>(body: &Value, path: &str, err: Error) -> Error {
+  let fields = body.invalid_utf8_fields(path);
+  let decodes = |fields: &[String]| {
+    serde_json::to_value(body.with_lossy_utf8(fields, path))
+      .and_then(serde_json::from_value::<V>)
+      .is_ok()
+  };
+  if fields.is_empty() || !decodes(&fields) {
+    return err;
+  }
+  // Binary fields fail to decode as strings, look for the one that is meant to be one
+  let field = fields.iter().find(|field| {
+    let mut others = fields.clone();
+    others.retain(|other| other != *field);
+    !decodes(&others)
+  });
+  Error::InvalidUtf8(field.unwrap_or(&fields[0]).clone())
+}
+
+fn get_stream_bytes(body: &Value) -> Option<usize> {
+  match body {
+    Value::Object(map) => match map.get("stream_bytes") {
+      Some(Value::Number(number)) => number.as_u64().map(|n| n as usize),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+fn set_stream_bytes(body: &mut Value, length: usize) {
+  let length = Value::Number(serde_json::Number::from(length as u64));
+  match body {
+    Value::Object(map) => {
+      map.insert("stream_bytes".to_string(), length);
+    }
+    body => {
+      let mut map = std::collections::BTreeMap::new();
+      map.insert("stream_bytes".to_string(), length);
+      *body = Value::Object(map);
+    }
+  }
+}
+
+/// The `cmd` of a message, serialized by its canonical name.
+/// Names of ZeroNet's request handlers, such as `actionGetFile`
+/// for `getFile`, are accepted as aliases. Unrecognized commands
+/// are kept in `Other`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Command {
+  Handshake,
+  Response,
+  Ping,
+  GetFile,
+  StreamFile,
+  Pex,
+  Update,
+  ListModified,
+  GetHashfield,
+  SetHashfield,
+  FindHashIds,
+  Checkport,
+  Announce,
+  GetPieceFields,
+  SetPieceFields,
+  Other(String),
+}
+
+/// The names of every request command the crate knows about,
+/// each has its own `Command` variant.
+pub fn known_commands() -> &'static [&'static str] {
+  &[
+    "handshake",
+    "ping",
+    "announce",
+    "getFile",
+    "streamFile",
+    "pex",
+    "update",
+    "listModified",
+    "getHashfield",
+    "setHashfield",
+    "findHashIds",
+    "checkport",
+    "getPieceFields",
+    "setPieceFields",
+  ]
+}
+
+impl Command {
+  pub fn as_str(&self) -> &str {
+    match self {
+      Command::Handshake => "handshake",
+      Command::Response => "response",
+      Command::Ping => "ping",
+      Command::GetFile => "getFile",
+      Command::StreamFile => "streamFile",
+      Command::Pex => "pex",
+      Command::Update => "update",
+      Command::ListModified => "listModified",
+      Command::GetHashfield => "getHashfield",
+      Command::SetHashfield => "setHashfield",
+      Command::FindHashIds => "findHashIds",
+      Command::Checkport => "checkport",
+      Command::Announce => "announce",
+      Command::GetPieceFields => "getPieceFields",
+      Command::SetPieceFields => "setPieceFields",
+      Command::Other(other) => other,
+    }
   }
 }
 
+impl From<&str> for Command {
+  fn from(value: &str) -> Command {
+    let cmd = match value.strip_prefix("action") {
+      Some(handler) if handler.starts_with(|c: char| c.is_ascii_uppercase()) => {
+        let mut chars = handler.chars();
+        let first = chars.next().unwrap().to_ascii_lowercase();
+        std::iter::once(first).chain(chars).collect()
+      }
+      _ => value.to_string(),
+    };
+    match cmd.as_str() {
+      "handshake" => Command::Handshake,
+      "response" => Command::Response,
+      "ping" => Command::Ping,
+      "getFile" => Command::GetFile,
+      "streamFile" => Command::StreamFile,
+      "pex" => Command::Pex,
+      "update" => Command::Update,
+      "listModified" => Command::ListModified,
+      "getHashfield" => Command::GetHashfield,
+      "setHashfield" => Command::SetHashfield,
+      "findHashIds" => Command::FindHashIds,
+      "checkport" => Command::Checkport,
+      "announce" => Command::Announce,
+      "getPieceFields" => Command::GetPieceFields,
+      "setPieceFields" => Command::SetPieceFields,
+      _ => Command::Other(value.to_string()),
+    }
+  }
+}
+
+impl Serialize for Command {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(self.as_str())
+  }
+}
+
+impl<'de> Deserialize<'de> for Command {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Command, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(Command::from(value.as_str()))
+  }
+}
+
+/// The params of a request decoded into the template of its command,
+/// see `decode_request_params`. Unrecognized commands, and those
+/// without a template yet, keep their params as a `Value`.
+#[derive(Debug)]
+pub enum RequestType {
+  Handshake(templates::Handshake),
+  Ping,
+  Announce(templates::Announce),
+  GetFile(templates::GetFile),
+  StreamFile(templates::StreamFile),
+  Pex(templates::Pex),
+  Update(templates::UpdateFile),
+  GetHashfield(templates::GetHashfield),
+  SetHashfield(templates::SetHashfield),
+  Checkport(templates::Checkport),
+  Other(Value),
+}
+
+/// Decode the params of `req` into the template `cmd` maps to,
+/// aliases such as `actionGetFile` included.
+pub fn decode_request_params(cmd: &str, req: &Request) -> Result<RequestType, Error> {
+  let decoded = match Command::from(cmd) {
+    Command::Handshake => RequestType::Handshake(req.body()?),
+    Command::Ping => RequestType::Ping,
+    Command::Announce => RequestType::Announce(req.body()?),
+    Command::GetFile => RequestType::GetFile(req.body()?),
+    Command::StreamFile => RequestType::StreamFile(req.body()?),
+    Command::Pex => RequestType::Pex(req.body()?),
+    Command::Update => RequestType::Update(req.body()?),
+    Command::GetHashfield => RequestType::GetHashfield(req.body()?),
+    Command::SetHashfield => RequestType::SetHashfield(req.body()?),
+    Command::Checkport => RequestType::Checkport(req.body()?),
+    _ => RequestType::Other(req.params.clone()),
+  };
+  Ok(decoded)
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(untagged, rename_all = "camelCase")]
 pub enum ZeroMessage {
@@ -58,17 +434,81 @@ impl ZeroMessage {
       cmd: cmd.to_string(),
       req_id,
       params: serde_json::from_value(serde_json::to_value(body).unwrap()).unwrap(),
+      stream: None,
     };
     ZeroMessage::Request(request)
   }
+  /// A request sent without `req_id`, telling the peer
+  /// not to respond to it.
+  pub fn notification<V: DeserializeOwned + Serialize>(cmd: &str, body: V) -> ZeroMessage {
+    ZeroMessage::request(cmd, NOTIFICATION_REQ_ID, body)
+  }
   pub fn response<V: DeserializeOwned + Serialize>(to: usize, body: V) -> ZeroMessage {
     let response = Response {
       cmd: "response".to_string(),
       to,
       response: serde_json::from_value(serde_json::to_value(body).unwrap()).unwrap(),
+      stream: None,
     };
     ZeroMessage::Response(response)
   }
+  /// Like `request`, but fields that are normally skipped
+  /// when they hold their default value are sent anyway,
+  /// for peers that require them to be present.
+  pub fn request_with_defaults<V: DeserializeOwned + Serialize>(
+    cmd: &str,
+    req_id: usize,
+    body: V,
+  ) -> ZeroMessage {
+    with_defaults(|| ZeroMessage::request(cmd, req_id, body))
+  }
+  /// Like `response`, but keeps default fields, see `request_with_defaults`.
+  pub fn response_with_defaults<V: DeserializeOwned + Serialize>(
+    to: usize,
+    body: V,
+  ) -> ZeroMessage {
+    with_defaults(|| ZeroMessage::response(to, body))
+  }
+  /// Encode the message to bytes, in the named MessagePack
+  /// format used on the wire.
+  pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+    Ok(rmp_serde::to_vec_named(self)?)
+  }
+  /// The number of bytes `to_vec` would produce, computed
+  /// without buffering the encoded message.
+  pub fn encoded_len(&self) -> Result<usize, Error> {
+    let mut counter = ByteCounter(0);
+    rmp_serde::encode::write_named(&mut counter, self)?;
+    Ok(counter.0)
+  }
+  /// Decode a message from bytes, see `to_vec`.
+  /// Arrays and maps are limited to `DEFAULT_MAX_ITEMS` items,
+  /// other values to `DEFAULT_MAX_LEN` bytes.
+  pub fn from_slice(bytes: &[u8]) -> Result<ZeroMessage, Error> {
+    frame_len(bytes)?;
+    Ok(rmp_serde::from_slice(bytes)?)
+  }
+  /// Decode every complete message in `bytes`, together with the
+  /// raw bytes announced by their `stream_bytes`. The bytes of an
+  /// incomplete message at the end are returned as leftover.
+  pub fn from_slice_many(bytes: &[u8]) -> Result<(Vec<ZeroMessage>, &[u8]), Error> {
+    let mut messages = vec![];
+    let mut rest = bytes;
+    while let Some(length) = frame_len(rest)? {
+      let mut message = ZeroMessage::from_slice(&rest[..length])?;
+      let mut end = length;
+      if let Some(stream_bytes) = message.stream_bytes() {
+        if rest.len() < length + stream_bytes {
+          break;
+        }
+        end += stream_bytes;
+        message.set_stream(rest[length..end].to_vec());
+      }
+      messages.push(message);
+      rest = &rest[end..];
+    }
+    Ok((messages, rest))
+  }
   pub fn body<V: DeserializeOwned + Serialize>(self) -> Result<V, Error> {
     match self {
       ZeroMessage::Response(res) => res.body(),
@@ -77,6 +517,57 @@ impl ZeroMessage {
   }
 }
 
+/// Any message as plain JSON, for relays and debugging tools that
+/// do not care about templates, use it as `Connection<JsonMessage>`.
+/// Binary fields are decoded as base64 strings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "Value")]
+pub struct JsonMessage(pub serde_json::Value);
+
+impl From<Value> for JsonMessage {
+  fn from(value: Value) -> JsonMessage {
+    JsonMessage(value.into())
+  }
+}
+
+impl Requestable for JsonMessage {
+  type Key = usize;
+
+  fn req_id(&self) -> Option<Self::Key> {
+    self.0.get("req_id")?.as_u64().map(|id| id as usize)
+  }
+  fn to(&self) -> Option<Self::Key> {
+    self.0.get("to")?.as_u64().map(|id| id as usize)
+  }
+  fn stream_bytes(&self) -> Option<usize> {
+    let stream_bytes = match self.0.get("params") {
+      Some(params) if self.is_request() => params.get("stream_bytes"),
+      _ => self.0.get("stream_bytes"),
+    };
+    stream_bytes?.as_u64().map(|n| n as usize)
+  }
+  fn set_stream(&mut self, stream: Vec<u8>) {
+    if let serde_json::Value::Object(map) = &mut self.0 {
+      let stream = serde_json::Value::String(base64::encode(stream));
+      map.insert("stream".to_string(), stream);
+    }
+  }
+}
+
+/// A writer that only counts the bytes written to it.
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0 += buf.len();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
 /// ```
 /// use zeronet_protocol::message::ZeroMessage;
 /// use zeronet_protocol::requestable::Requestable;
@@ -94,7 +585,7 @@ impl Requestable for ZeroMessage {
 
   fn req_id(&self) -> Option<Self::Key> {
     match self {
-      ZeroMessage::Request(req) => Some(req.req_id),
+      ZeroMessage::Request(req) if !req.is_notification() => Some(req.req_id),
       _ => None,
     }
   }
@@ -104,6 +595,18 @@ impl Requestable for ZeroMessage {
       _ => None,
     }
   }
+  fn stream_bytes(&self) -> Option<usize> {
+    match self {
+      ZeroMessage::Response(res) => res.stream_bytes(),
+      ZeroMessage::Request(req) => req.stream_bytes(),
+    }
+  }
+  fn set_stream(&mut self, stream: Vec<u8>) {
+    match self {
+      ZeroMessage::Response(res) => res.stream = Some(ByteBuf::from(stream)),
+      ZeroMessage::Request(req) => req.stream = Some(ByteBuf::from(stream)),
+    }
+  }
 }
 
 #[cfg(test)]
@@ -117,7 +620,7 @@ mod tests {
   }
 
   fn rmps(msg: &ZeroMessage) -> Vec<u8> {
-    let jsoned = serde_json::to_value(&msg).unwrap();
+    let jsoned = serde_json::to_value(msg).unwrap();
     rmp_serde::to_vec_named(&jsoned).unwrap()
 
     // rmp_serde::to_vec_named(msg).unwrap()
@@ -187,6 +690,507 @@ mod tests {
   #[test]
   fn test_announce_msgpack() {}
 
+  #[test]
+  fn test_integer_params() {
+    let large = (1u64 << 53) + 1;
+    let params = serde_json::json!({ "port": 15441, "large": large, "negative": -42 });
+    let msg = ZeroMessage::request("announce", 0, params);
+    let bytes = msg.to_vec().unwrap();
+
+    let raw = rmpv::decode::read_value(&mut &bytes[..]).unwrap();
+    let raw_params = raw["params"].clone();
+    assert!(raw_params["port"].is_u64());
+    assert!(raw_params["large"].is_u64());
+    assert!(raw_params["negative"].is_i64());
+
+    let params: serde_json::Value = ZeroMessage::from_slice(&bytes).unwrap().body().unwrap();
+    assert_eq!(params["port"].as_u64(), Some(15441));
+    assert_eq!(params["large"].as_u64(), Some(large));
+    assert_eq!(params["negative"].as_i64(), Some(-42));
+  }
+
+  #[test]
+  fn test_deterministic_encoding() {
+    use crate::templates::{Announce, TransportType};
+    use serde_bytes::ByteBuf;
+
+    let announce = Announce {
+      port: 15441,
+      add: vec![TransportType::Onion, TransportType::IPV4],
+      need_types: vec![TransportType::IPV4],
+      need_num: Some(20),
+      hashes: vec![ByteBuf::from(vec![1u8; 32]), ByteBuf::from(vec![2u8; 32])],
+      onions: vec!["zp2ynpztyxj2kw7x".to_string()],
+      delete: true,
+      ..Default::default()
+    };
+    let template_bytes = rmp_serde::to_vec_named(&announce).unwrap();
+    let message_bytes =
+      rmp_serde::to_vec_named(&ZeroMessage::request("announce", 0, announce.clone())).unwrap();
+
+    for _ in 0..100 {
+      let msg = ZeroMessage::request("announce", 0, announce.clone());
+      assert_eq!(rmp_serde::to_vec_named(&announce).unwrap(), template_bytes);
+      assert_eq!(rmp_serde::to_vec_named(&msg).unwrap(), message_bytes);
+    }
+  }
+
+  #[test]
+  fn test_to_vec_from_slice() {
+    use crate::templates::GetFile;
+
+    let body = GetFile {
+      site:       "1ADDR".to_string(),
+      inner_path: "content.json".to_string(),
+      location:   0,
+      file_size:  1234,
+    };
+    let msg = ZeroMessage::request("getFile", 3, body);
+    let bytes = msg.to_vec().unwrap();
+    assert_eq!(bytes, rmp_serde::to_vec_named(&msg).unwrap());
+    assert_eq!(ZeroMessage::from_slice(&bytes).unwrap(), msg);
+
+    assert!(ZeroMessage::from_slice(&bytes[..bytes.len() - 1]).is_err());
+  }
+
+  #[test]
+  fn test_positional_response() {
+    use super::templates::PingResponse;
+
+    let mut body = std::collections::BTreeMap::new();
+    body.insert("body", "Pong!");
+    let bytes = rmp_serde::to_vec(&("response", 1, body)).unwrap();
+
+    let msg = ZeroMessage::from_slice(&bytes).unwrap();
+    assert!(msg.is_response());
+    assert_eq!(msg.to(), Some(1));
+    let body: PingResponse = msg.body().unwrap();
+    assert_eq!(body.body, "Pong!");
+  }
+
+  #[test]
+  fn test_fixture_constructors() {
+    use super::templates::PingResponse;
+    use super::value::Value;
+    use super::{Request, Response};
+
+    let request = Request::new("ping", 4, Value::Null);
+    assert_eq!(ZeroMessage::Request(request).req_id(), Some(4));
+
+    let mut body = std::collections::BTreeMap::new();
+    body.insert("body".to_string(), Value::String("Pong!".to_string()));
+    let response = Response::new(4, Value::Object(body));
+    assert_eq!(response.to, 4);
+    let body: PingResponse = response.body().unwrap();
+    assert_eq!(body.body, "Pong!");
+  }
+
+  #[test]
+  fn test_notification() {
+    let msg = ZeroMessage::notification("pex", ());
+    let json = serde_json::to_value(&msg).unwrap();
+    assert!(json.get("req_id").is_none());
+    assert_eq!(msg.req_id(), None);
+
+    let msg = ZeroMessage::from_slice(&msg.to_vec().unwrap()).unwrap();
+    match msg {
+      ZeroMessage::Request(req) => assert!(req.is_notification()),
+      _ => panic!("not a request"),
+    }
+    match des(r#"{"cmd": "pex", "req_id": 0}"#).unwrap() {
+      ZeroMessage::Request(req) => assert!(!req.is_notification()),
+      _ => panic!("not a request"),
+    }
+  }
+
+  #[test]
+  fn test_response_raw() {
+    use super::templates::PingResponse;
+    use super::value::Value;
+
+    let body = serde_json::json!({"body": "Pong!", "extra": {"peers": 2}});
+    let bytes = ZeroMessage::response(3, body).to_vec().unwrap();
+    let msg = ZeroMessage::from_slice(&bytes).unwrap();
+    let res = match msg {
+      ZeroMessage::Response(res) => res,
+      _ => panic!("not a response"),
+    };
+
+    let ping: PingResponse = res.body().unwrap();
+    assert_eq!(ping.body, "Pong!");
+    match res.raw() {
+      Value::Object(fields) => assert!(fields.contains_key("extra")),
+      _ => panic!("not an object"),
+    }
+    assert_eq!(ZeroMessage::Response(res).to_vec().unwrap(), bytes);
+  }
+
+  #[test]
+  fn test_response_without_cmd() {
+    use super::templates::PingResponse;
+
+    let msg = des(r#"{"to": 2, "body": "Pong!"}"#).unwrap();
+    assert!(msg.is_response());
+    assert_eq!(msg.to(), Some(2));
+    match &msg {
+      ZeroMessage::Response(res) => assert_eq!(res.cmd, "response"),
+      _ => panic!("not a response"),
+    }
+    let body: PingResponse = msg.body().unwrap();
+    assert_eq!(body.body, "Pong!");
+
+    let mut body = std::collections::BTreeMap::new();
+    body.insert("to", 2);
+    let msg = ZeroMessage::from_slice(&rmp_serde::to_vec_named(&body).unwrap()).unwrap();
+    assert_eq!(msg.to(), Some(2));
+  }
+
+  #[test]
+  fn test_from_slice_many() {
+    use crate::templates::{GetFile, PingResponse, StreamFileResponse};
+
+    let body = GetFile {
+      site:       "1ADDR".to_string(),
+      inner_path: "content.json".to_string(),
+      location:   0,
+      file_size:  1234,
+    };
+    let messages = [
+      ZeroMessage::request("ping", 0, ()),
+      ZeroMessage::request("getFile", 1, body),
+      ZeroMessage::response(0, PingResponse::default()),
+    ];
+    let mut bytes = vec![];
+    for message in messages.iter() {
+      bytes.extend(message.to_vec().unwrap());
+    }
+    let (decoded, leftover) = ZeroMessage::from_slice_many(&bytes).unwrap();
+    assert_eq!(decoded, messages);
+    assert!(leftover.is_empty());
+
+    let mut stream = ZeroMessage::response(1, StreamFileResponse::default());
+    if let ZeroMessage::Response(response) = &mut stream {
+      response.set_stream_bytes(3);
+    }
+    bytes.extend(stream.to_vec().unwrap());
+    bytes.extend([1, 2]);
+    let (decoded, leftover) = ZeroMessage::from_slice_many(&bytes).unwrap();
+    assert_eq!(decoded.len(), 3);
+    assert_eq!(leftover, &bytes[bytes.len() - leftover.len()..]);
+    assert_eq!(leftover.len(), stream.to_vec().unwrap().len() + 2);
+
+    bytes.push(3);
+    let (decoded, leftover) = ZeroMessage::from_slice_many(&bytes).unwrap();
+    assert_eq!(decoded.len(), 4);
+    assert!(leftover.is_empty());
+    let mut stream = decoded.into_iter().last().unwrap();
+    if let ZeroMessage::Response(response) = &mut stream {
+      assert_eq!(response.take_stream(), Some(vec![1, 2, 3]));
+    }
+  }
+
+  #[test]
+  fn test_json_message() {
+    use super::JsonMessage;
+    use crate::async_connection::Connection;
+    use crate::templates::Announce;
+    use crate::testing::pipe;
+    use futures::executor::block_on;
+    use serde_bytes::ByteBuf;
+    use std::io::Write;
+
+    let (reader, mut writer) = pipe();
+    let mut connection: Connection<JsonMessage> =
+      Connection::new(Box::new(reader), Box::new(std::io::sink()));
+
+    let announce = Announce {
+      port: 15441,
+      hashes: vec![ByteBuf::from(vec![0xff, 0x00, 0x80])],
+      ..Default::default()
+    };
+    let msg = ZeroMessage::request("announce", 5, announce);
+    writer.write_all(&msg.to_vec().unwrap()).unwrap();
+
+    let message = block_on(connection.recv()).unwrap();
+    assert_eq!(message.req_id(), Some(5));
+    assert_eq!(message.0["cmd"], "announce");
+    assert_eq!(message.0["params"]["port"], 15441);
+    assert_eq!(message.0["params"]["hashes"], serde_json::json!(["/wCA"]));
+  }
+
+  #[test]
+  fn test_command() {
+    use super::Command;
+
+    let msg = ZeroMessage::request("actionGetFile", 0, ());
+    let aliased = match rmpd(rmps(&msg)) {
+      ZeroMessage::Request(request) => request.command(),
+      _ => unreachable!(),
+    };
+    assert_eq!(aliased, Command::GetFile);
+    assert_eq!(Command::from("getFile"), Command::GetFile);
+    assert_eq!(Command::from("actionGetFile").as_str(), "getFile");
+    assert_eq!(Command::from("actionStreamFile"), Command::StreamFile);
+
+    assert_eq!(
+      Command::from("actionbar"),
+      Command::Other("actionbar".to_string())
+    );
+    assert_eq!(Command::from("getfile").as_str(), "getfile");
+    let command: Command = serde_json::from_str(r#""actionPing""#).unwrap();
+    assert_eq!(serde_json::to_string(&command).unwrap(), r#""ping""#);
+  }
+
+  #[test]
+  fn test_known_commands() {
+    use super::{known_commands, Command};
+
+    let variants = [
+      Command::Handshake,
+      Command::Ping,
+      Command::GetFile,
+      Command::StreamFile,
+      Command::Pex,
+      Command::Update,
+      Command::ListModified,
+      Command::GetHashfield,
+      Command::SetHashfield,
+      Command::FindHashIds,
+      Command::Checkport,
+      Command::Announce,
+      Command::GetPieceFields,
+      Command::SetPieceFields,
+    ];
+    assert_eq!(known_commands().len(), variants.len());
+    for variant in variants.iter() {
+      assert!(known_commands().contains(&variant.as_str()));
+    }
+    for name in known_commands() {
+      assert_eq!(Command::from(*name).as_str(), *name);
+    }
+  }
+
+  #[test]
+  fn test_encoded_len() {
+    use crate::templates::{Handshake, PingResponse};
+
+    let messages = vec![
+      ZeroMessage::request("ping", 0, ()),
+      ZeroMessage::request("handshake", 1, Handshake::new()),
+      ZeroMessage::response(1, Handshake::new()),
+      ZeroMessage::response(
+        2,
+        PingResponse {
+          body: "Pong!".to_string(),
+        },
+      ),
+    ];
+    for msg in messages {
+      assert_eq!(msg.encoded_len().unwrap(), msg.to_vec().unwrap().len());
+    }
+  }
+
+  #[test]
+  fn test_announce_onions() {
+    use crate::address::ParseError;
+    use crate::templates::Announce;
+
+    let mut announce = Announce::default();
+    announce.add_onion("zp2ynpztyxj2kw7x").unwrap();
+    announce
+      .add_onion("pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion")
+      .unwrap();
+    assert!(announce.add_onion("zp2ynpztyxj2kw7").is_err());
+    assert!(announce.add_onion("zp2ynpztyxj2kw7!").is_err());
+    assert!(announce.add_onion("zp2ynpztyxj2kw71").is_err());
+    // A v3 address with the wrong version byte
+    let onion = "trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgia";
+    let result = announce.add_onion(onion);
+    assert!(matches!(result, Err(ParseError::InvalidOnionV3(_))));
+
+    let msg = ZeroMessage::request("announce", 0, announce);
+    let params: serde_json::Value = rmpd(rmps(&msg)).body().unwrap();
+    assert_eq!(
+      params["onions"],
+      serde_json::json!([
+        "zp2ynpztyxj2kw7x",
+        "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd"
+      ])
+    );
+  }
+
+  #[test]
+  fn test_announce_need_num() {
+    use crate::templates::Announce;
+
+    let msg = ZeroMessage::request("announce", 0, Announce::default());
+    let params: serde_json::Value = rmpd(rmps(&msg)).body().unwrap();
+    assert!(params.get("need_num").is_none());
+
+    let announce = Announce {
+      need_num: Some(0),
+      ..Default::default()
+    };
+    let msg = ZeroMessage::request("announce", 0, announce);
+    let params: serde_json::Value = rmpd(rmps(&msg)).body().unwrap();
+    assert_eq!(params["need_num"], 0);
+    let announce: Announce = rmpd(rmps(&msg)).body().unwrap();
+    assert_eq!(announce.need_num, Some(0));
+  }
+
+  #[test]
+  fn test_announce_hashes_packed() {
+    use crate::templates::Announce;
+    use serde_bytes::ByteBuf;
+
+    let hashes: Vec<ByteBuf> = (0..10u8).map(|i| ByteBuf::from(vec![i; 32])).collect();
+    let announce = Announce {
+      hashes: hashes.clone(),
+      ..Default::default()
+    };
+    let unpacked = ZeroMessage::request("announce", 0, announce.clone());
+    let mut announce = announce;
+    announce.pack_hashes().unwrap();
+    assert!(announce.hashes.is_empty());
+    let packed = ZeroMessage::request("announce", 0, announce);
+    assert!(packed.encoded_len().unwrap() < unpacked.encoded_len().unwrap());
+
+    let bytes = packed.to_vec().unwrap();
+    let announce: Announce = ZeroMessage::from_slice(&bytes).unwrap().body().unwrap();
+    assert_eq!(announce.all_hashes().unwrap(), hashes);
+
+    // Only whole hashes are packed or unpacked
+    let mut announce = Announce {
+      hashes: vec![ByteBuf::from(vec![0; 20])],
+      ..Default::default()
+    };
+    let result = announce.pack_hashes();
+    assert!(matches!(result, Err(crate::Error::InvalidHashLength(20))));
+    assert_eq!(announce.hashes.len(), 1);
+    announce.hashes_packed = Some(ByteBuf::from(vec![0; 40]));
+    let result = announce.all_hashes();
+    assert!(matches!(result, Err(crate::Error::InvalidHashLength(8))));
+  }
+
+  #[test]
+  fn test_announce_delete() {
+    use crate::templates::{Announce, AnnounceResponse};
+    use serde_bytes::ByteBuf;
+
+    let hashes = vec![ByteBuf::from(vec![0xff; 32]), ByteBuf::from(vec![0xfe; 32])];
+    let mut announce = Announce {
+      port: 15441,
+      ..Default::default()
+    };
+    announce.delete_sites(hashes.clone());
+    let msg = ZeroMessage::request("announce", 0, announce);
+    let announce: Announce = ZeroMessage::from_slice(&msg.to_vec().unwrap())
+      .unwrap()
+      .body()
+      .unwrap();
+    assert!(announce.delete);
+    assert_eq!(announce.port, 15441);
+    assert_eq!(announce.hashes, hashes);
+
+    let text = r#"{"cmd": "response", "to": 0, "peers": []}"#;
+    let response: AnnounceResponse = des(text).unwrap().body().unwrap();
+    assert!(response.peers.is_empty());
+    let bytes = ZeroMessage::response(0, response).to_vec().unwrap();
+    let response: AnnounceResponse = ZeroMessage::from_slice(&bytes).unwrap().body().unwrap();
+    assert!(response.peers.is_empty());
+  }
+
+  #[test]
+  fn test_announce_response_shapes() {
+    use crate::address::AddrFamily;
+    use crate::templates::{AnnouncePeers, AnnounceResponse};
+    use crate::PeerAddr;
+    use serde_bytes::ByteBuf;
+
+    let ipv4 = PeerAddr::parse("1.2.3.4:15441").unwrap();
+    let ipv6 = PeerAddr::parse("[::2]:15441").unwrap();
+    let structured = AnnounceResponse {
+      peers: vec![AnnouncePeers {
+        ip_v4: vec![ByteBuf::from(ipv4.pack())],
+        ip_v6: vec![ByteBuf::from(ipv6.pack())],
+        ..Default::default()
+      }],
+    };
+    let bytes = ZeroMessage::response(0, structured).to_vec().unwrap();
+    let response: AnnounceResponse = ZeroMessage::from_slice(&bytes).unwrap().body().unwrap();
+    assert_eq!(response.peers_of(AddrFamily::IPV4), vec![ipv4.clone()]);
+    assert_eq!(response.peers_of(AddrFamily::IPV6), vec![ipv6.clone()]);
+
+    let flat = serde_json::json!({
+      "peers": [ByteBuf::from(ipv4.pack()), ByteBuf::from(ipv6.pack())],
+    });
+    let bytes = ZeroMessage::response(0, flat).to_vec().unwrap();
+    let response: AnnounceResponse = ZeroMessage::from_slice(&bytes).unwrap().body().unwrap();
+    assert_eq!(response.peers.len(), 1);
+    assert_eq!(response.peers_of(AddrFamily::IPV4), vec![ipv4]);
+    assert_eq!(response.peers_of(AddrFamily::IPV6), vec![ipv6]);
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_announce_peers_of() {
+    use crate::address::AddrFamily;
+    use crate::templates::{AnnouncePeers, AnnounceResponse};
+    use crate::PeerAddr;
+    use serde_bytes::ByteBuf;
+
+    let pack = |address: &str| ByteBuf::from(PeerAddr::parse(address).unwrap().pack());
+    let onion = "zp2ynpztyxj2kw7x.onion:15441";
+    let onion_v3 = "trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid.onion:15441";
+    let response = AnnounceResponse {
+      peers: vec![
+        AnnouncePeers {
+          ip_v4: vec![pack("1.2.3.4:15441")],
+          ip_v6: vec![pack("[::2]:15441")],
+          onion_v2: vec![pack(onion)],
+          ..Default::default()
+        },
+        AnnouncePeers {
+          ip_v4: vec![pack("5.6.7.8:15441")],
+          onion_v3: vec![pack(onion_v3)],
+          ..Default::default()
+        },
+      ],
+    };
+
+    let onions = response.peers_of(AddrFamily::Onion);
+    assert_eq!(onions.len(), 2);
+    assert_eq!(onions[0], PeerAddr::parse(onion).unwrap());
+    assert_eq!(onions[1], PeerAddr::parse(onion_v3).unwrap());
+    assert_eq!(response.peers_of(AddrFamily::IPV4).len(), 2);
+    assert!(response.peers_of(AddrFamily::I2P).is_empty());
+  }
+
+  #[test]
+  fn test_transport_types() {
+    use crate::templates::{Announce, TransportType};
+
+    let need_types = vec![
+      TransportType::IPV4,
+      TransportType::IPV6,
+      TransportType::Onion,
+      TransportType::Other("i2p".to_string()),
+    ];
+    let announce = Announce {
+      need_types: need_types.clone(),
+      ..Default::default()
+    };
+    let json = serde_json::to_value(&announce).unwrap();
+    assert_eq!(
+      json["need_types"],
+      serde_json::json!(["ipv4", "ipv6", "onion", "i2p"])
+    );
+
+    let msg = ZeroMessage::request("announce", 0, announce);
+    let announce: Announce = rmpd(rmps(&msg)).body().unwrap();
+    assert_eq!(announce.need_types, need_types);
+  }
+
   #[test]
   fn test_get_file() {
     let text = r#"
@@ -196,10 +1200,28 @@ mod tests {
 			"params": {}
 		}"#;
     let msg = des(text).unwrap();
-    assert_eq!(msg.is_request(), true);
+    assert!(msg.is_request());
     assert_eq!(rmpd(rmps(&msg)), msg);
   }
 
+  #[test]
+  fn test_get_file_params_shapes() {
+    use crate::templates::GetFile;
+
+    for text in [
+      r#"{"cmd": "getFile", "req_id": 0, "params": {}}"#,
+      r#"{"cmd": "getFile", "req_id": 0, "params": null}"#,
+      r#"{"cmd": "getFile", "req_id": 0}"#,
+    ] {
+      let msg = des(text).unwrap();
+      let body: GetFile = rmpd(msg.to_vec().unwrap()).body().unwrap();
+      assert_eq!(body.site, "");
+      assert_eq!(body.location, 0);
+    }
+    let ping = des(r#"{"cmd": "ping", "req_id": 0}"#).unwrap();
+    assert!(ping.body::<()>().is_ok());
+  }
+
   #[test]
   fn test_get_file_response() {
     let msg = des(
@@ -212,7 +1234,151 @@ mod tests {
 			"size": 1132
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true, "Deserializes response");
+    assert!(msg.is_ok(), "Deserializes response");
+  }
+
+  #[test]
+  fn test_get_file_response_str_or_bin_body() {
+    use crate::templates::GetFileResponse;
+    use rmpv::Value as MsgValue;
+
+    let text = b"content.json content";
+    let bodies: Vec<(MsgValue, &[u8])> = vec![
+      // Sent as str
+      (MsgValue::from("content.json content"), text),
+      // Sent as bin, both UTF-8 and not
+      (MsgValue::from(&text[..]), text),
+      (MsgValue::from(&[0xff, 0x00, 0x80][..]), &[0xff, 0x00, 0x80]),
+    ];
+    for (body, expected) in bodies {
+      let msg = MsgValue::Map(vec![
+        (MsgValue::from("cmd"), MsgValue::from("response")),
+        (MsgValue::from("to"), MsgValue::from(1)),
+        (MsgValue::from("body"), body),
+        (MsgValue::from("location"), MsgValue::from(20)),
+        (MsgValue::from("size"), MsgValue::from(20)),
+      ]);
+      let mut bytes = vec![];
+      rmpv::encode::write_value(&mut bytes, &msg).unwrap();
+
+      let response: GetFileResponse = ZeroMessage::from_slice(&bytes).unwrap().body().unwrap();
+      assert_eq!(response.body.as_slice(), expected);
+    }
+  }
+
+  #[test]
+  fn test_body_decodes_by_template() {
+    use crate::templates::{GetFileResponse, PingResponse};
+    use rmpv::Value as MsgValue;
+
+    // `body` is a str in pongs and bin in file responses, but peers
+    // mix them up, either decodes into both templates
+    let bodies = vec![MsgValue::from("Pong!"), MsgValue::from(&b"Pong!"[..])];
+    for body in bodies {
+      let msg = MsgValue::Map(vec![
+        (MsgValue::from("cmd"), MsgValue::from("response")),
+        (MsgValue::from("to"), MsgValue::from(0)),
+        (MsgValue::from("body"), body),
+      ]);
+      let mut bytes = vec![];
+      rmpv::encode::write_value(&mut bytes, &msg).unwrap();
+      let msg = ZeroMessage::from_slice(&bytes).unwrap();
+
+      let pong: PingResponse = msg.clone().body().unwrap();
+      assert_eq!(pong.body, "Pong!");
+      let file: GetFileResponse = msg.body().unwrap();
+      assert_eq!(file.body.as_slice(), b"Pong!");
+    }
+
+    // Only bytes that are not UTF-8 cannot be a pong
+    let file = GetFileResponse {
+      body: serde_bytes::ByteBuf::from(vec![0xff, 0x00]),
+      ..Default::default()
+    };
+    let bytes = ZeroMessage::response(0, file).to_vec().unwrap();
+    let msg = ZeroMessage::from_slice(&bytes).unwrap();
+    assert!(msg.clone().body::<PingResponse>().is_err());
+    let file: GetFileResponse = msg.body().unwrap();
+    assert_eq!(file.body.as_slice(), &[0xff, 0x00]);
+  }
+
+  #[test]
+  fn test_body_invalid_utf8() {
+    use crate::templates::Handshake;
+    use crate::Error::InvalidUtf8;
+    use serde_bytes::ByteBuf;
+
+    let mut handshake = Handshake::new();
+    handshake.onion = Some("zp2ynpztyxj2kw7x".to_string());
+    handshake.hashfield_raw = Some(ByteBuf::from(vec![0xff, 0x00]));
+    let message = ZeroMessage::request("handshake", 0, handshake);
+    let mut bytes = message.to_vec().unwrap();
+    let onion = bytes.windows(4).position(|w| w == b"zp2y").unwrap();
+    bytes[onion..onion + 2].copy_from_slice(&[0xff, 0xfe]);
+
+    // The binary `hashfield_raw` is not the field at fault
+    let msg = ZeroMessage::from_slice(&bytes).unwrap();
+    let result = msg.body::<Handshake>();
+    assert!(matches!(result, Err(InvalidUtf8(field)) if field == "params.onion"));
+  }
+
+  #[test]
+  fn test_get_file_chunks() {
+    use crate::templates::GetFile;
+
+    let chunks = GetFile::chunks("1Site", "big.bin", 1000, 300);
+    let locations: Vec<usize> = chunks.iter().map(|chunk| chunk.location).collect();
+    assert_eq!(locations, vec![0, 300, 600, 900]);
+    assert!(chunks.iter().all(|chunk| chunk.file_size == 1000));
+    assert!(chunks.iter().all(|chunk| chunk.inner_path == "big.bin"));
+
+    let chunks = GetFile::chunks("1Site", "even.bin", 600, 300);
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(chunks[1].location + 300, 600);
+    assert!(GetFile::chunks("1Site", "empty.bin", 0, 300).is_empty());
+  }
+
+  #[test]
+  fn test_inner_path_normalized() {
+    use crate::templates::{GetFile, StreamFile, UpdateFile};
+
+    let get_file = GetFile {
+      inner_path: "data\\users\\content.json".to_string(),
+      ..Default::default()
+    };
+    let json = serde_json::to_value(ZeroMessage::request("getFile", 0, get_file)).unwrap();
+    assert_eq!(json["params"]["inner_path"], "data/users/content.json");
+
+    let stream_file = StreamFile {
+      inner_path: "css\\all.css".to_string(),
+      ..Default::default()
+    };
+    let json = serde_json::to_value(stream_file).unwrap();
+    assert_eq!(json["inner_path"], "css/all.css");
+    let update = UpdateFile {
+      inner_path: "data\\content.json".to_string(),
+      ..Default::default()
+    };
+    let json = serde_json::to_value(update).unwrap();
+    assert_eq!(json["inner_path"], "data/content.json");
+
+    let chunks = GetFile::chunks("1Site", "img\\big.png", 10, 10);
+    assert_eq!(chunks[0].inner_path, "img/big.png");
+  }
+
+  #[test]
+  fn test_get_file_response_defaults() {
+    use crate::templates::GetFileResponse;
+
+    let msg = des(r#"{"size": 20, "body": "content.json content", "to": 1}"#).unwrap();
+    let body: GetFileResponse = msg.body().unwrap();
+    assert_eq!(body.body.as_slice(), b"content.json content");
+    assert_eq!(body.location, 0);
+    assert_eq!(body.size, 20);
+
+    let msg = des(r#"{"cmd": "response", "to": 1, "body": ""}"#).unwrap();
+    let body: GetFileResponse = msg.body().unwrap();
+    assert_eq!((body.location, body.size), (0, 0));
   }
 
   #[test]
@@ -236,7 +1402,201 @@ mod tests {
 			}
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
+  }
+
+  #[test]
+  fn test_handshake_version() {
+    use crate::templates::Handshake;
+
+    let handshake = Handshake::new_with_version("0.7.6", 4565);
+    let json = serde_json::to_value(&handshake).unwrap();
+    assert_eq!(json["version"], "0.7.6");
+    assert_eq!(json["rev"], 4565);
+
+    let handshake = Handshake::new();
+    let json = serde_json::to_value(&handshake).unwrap();
+    assert_eq!(json["version"], "0.7");
+    assert_eq!(json["rev"], 4486);
+  }
+
+  #[test]
+  fn test_handshake_builder() {
+    use crate::templates::Handshake;
+
+    let handshake = Handshake::builder()
+      .peer_id("-ZN0070-abcdefghijkl")
+      .fileserver_port(15441)
+      .port_opened(true)
+      .crypt_supported(&["tls-rsa"])
+      .target_address("127.0.0.1:15441")
+      .version("0.7.6", 4565)
+      .build()
+      .unwrap();
+    let json = serde_json::to_value(&handshake).unwrap();
+    assert_eq!(json["peer_id"], "-ZN0070-abcdefghijkl");
+    assert_eq!(json["fileserver_port"], 15441);
+    assert_eq!(json["port_opened"], true);
+    assert_eq!(json["crypt_supported"], serde_json::json!(["tls-rsa"]));
+    assert_eq!(json["target_ip"], "127.0.0.1:15441");
+    assert_eq!(json["version"], "0.7.6");
+    assert_eq!(json["rev"], 4565);
+    assert_eq!(json["protocol"], "v2");
+
+    let handshake = Handshake::builder()
+      .onion("zp2ynpztyxj2kw7x")
+      .build()
+      .unwrap();
+    assert_eq!(handshake.onion.as_deref(), Some("zp2ynpztyxj2kw7x"));
+    let result = Handshake::builder()
+      .onion("zp2ynpztyxj2kw7x")
+      .peer_id("-ZN0070-abcdefghijkl")
+      .build();
+    assert!(matches!(result, Err(crate::Error::InvalidHandshake(_))));
+  }
+
+  #[test]
+  fn test_handshake_with_defaults() {
+    use crate::templates::Handshake;
+
+    let skipped = ZeroMessage::request("handshake", 0, Handshake::default());
+    let full = ZeroMessage::request_with_defaults("handshake", 0, Handshake::default());
+    let skipped = rmp_serde::to_vec_named(&skipped).unwrap();
+    let full = rmp_serde::to_vec_named(&full).unwrap();
+    assert!(skipped.len() < full.len());
+
+    let skipped: serde_json::Value = rmpd(skipped).body().unwrap();
+    let full: serde_json::Value = rmpd(full).body().unwrap();
+    assert!(skipped.get("rev").is_none());
+    assert!(skipped.get("crypt").is_none());
+    assert_eq!(full["rev"], 0);
+    assert_eq!(full["crypt"], serde_json::Value::Null);
+    assert!(full.get("crypt_supported").is_some());
+
+    // Outside of `request_with_defaults` fields are skipped again
+    let json = serde_json::to_value(Handshake::default()).unwrap();
+    assert!(json.get("rev").is_none());
+  }
+
+  #[test]
+  fn test_negotiated_crypt() {
+    use crate::templates::Handshake;
+
+    let mine = vec!["tls-ecc".to_string(), "tls-rsa".to_string()];
+    let mut peer = Handshake::new();
+
+    peer.crypt_supported = vec!["tls-rsa".to_string(), "tls-ecc".to_string()];
+    assert_eq!(peer.negotiated_crypt(&mine), Some("tls-ecc".to_string()));
+
+    peer.crypt_supported = vec!["tls-rsa".to_string()];
+    assert_eq!(peer.negotiated_crypt(&mine), Some("tls-rsa".to_string()));
+
+    peer.crypt_supported = vec!["tls-other".to_string()];
+    assert_eq!(peer.negotiated_crypt(&mine), None);
+    assert_eq!(peer.negotiated_crypt(&[]), None);
+
+    peer.crypt_supported = vec!["tls-rsa".to_string(), "tls-ecc".to_string()];
+    peer.crypt = Some("tls-rsa".to_string());
+    assert_eq!(peer.negotiated_crypt(&mine), Some("tls-rsa".to_string()));
+    peer.crypt = Some("tls-other".to_string());
+    assert_eq!(peer.negotiated_crypt(&mine), None);
+  }
+
+  #[test]
+  fn test_validate_response() {
+    use crate::templates::Handshake;
+    use crate::templates::HandshakeError::{AddressMismatch, ProtocolMismatch};
+
+    let mut request = Handshake::new();
+    request.target_address = Some("zp2ynpztyxj2kw7x.onion:15441".to_string());
+    let mut response = Handshake::new();
+    response.onion = Some("zp2ynpztyxj2kw7x".to_string());
+    assert!(response.validate_response(&request).is_ok());
+
+    response.onion = Some("boot3rdez4rzn36x".to_string());
+    let result = response.validate_response(&request);
+    assert!(matches!(result, Err(AddressMismatch { .. })));
+
+    response.onion = Some("zp2ynpztyxj2kw7x".to_string());
+    response.protocol = "v3".to_string();
+    let result = response.validate_response(&request);
+    assert!(matches!(result, Err(ProtocolMismatch { .. })));
+
+    // Clearnet targets are the peer's own address, nothing to compare
+    request.target_address = Some("127.0.0.1:15441".to_string());
+    response.protocol = "v2".to_string();
+    response.onion = None;
+    assert!(response.validate_response(&request).is_ok());
+  }
+
+  #[test]
+  fn test_target_peer_addr() {
+    use crate::templates::Handshake;
+    use crate::PeerAddr;
+
+    let mut handshake = Handshake::new();
+    assert_eq!(handshake.target_peer_addr(), None);
+    handshake.target_address = Some("192.168.1.13".to_string());
+    let expected = PeerAddr::parse("192.168.1.13:0").unwrap();
+    assert_eq!(handshake.target_peer_addr(), Some(expected));
+    handshake.target_address = Some("::1".to_string());
+    let expected = PeerAddr::parse("[::1]:0").unwrap();
+    assert_eq!(handshake.target_peer_addr(), Some(expected));
+    handshake.target_address = Some("not an address".to_string());
+    assert_eq!(handshake.target_peer_addr(), None);
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_target_peer_addr_onion() {
+    use crate::templates::Handshake;
+    use crate::PeerAddr;
+
+    let onion = "trackd5xiih3z7xyvvkyz2n65lehqziayjpxzsau3mwccwlelxrdrgid";
+    let text = format!(r#"{{"target_ip": "{}.onion"}}"#, onion);
+    let handshake: Handshake = serde_json::from_str(&text).unwrap();
+    let expected = PeerAddr::OnionV3(onion.to_string(), 0);
+    assert_eq!(handshake.target_peer_addr(), Some(expected));
+  }
+
+  #[test]
+  fn test_crypt() {
+    use crate::templates::{Crypt, Handshake};
+
+    let crypt: Crypt = serde_json::from_str(r#""tls-rsa""#).unwrap();
+    assert_eq!(crypt, Crypt::TlsRsa);
+    assert_eq!(serde_json::to_string(&crypt).unwrap(), r#""tls-rsa""#);
+    let other = Crypt::from("tls-other");
+    assert_eq!(other, Crypt::Other("tls-other".to_string()));
+    assert_eq!(other.as_str(), "tls-other");
+
+    let mut peer = Handshake::new();
+    peer.crypt_supported = vec!["tls-rsa".to_string(), "tls-other".to_string()];
+    assert_eq!(peer.crypts(), vec![Crypt::TlsRsa, other]);
+    let mine = [Crypt::TlsEcc, Crypt::TlsRsa];
+    assert_eq!(peer.negotiate(&mine), Some(Crypt::TlsRsa));
+    assert_eq!(peer.negotiate(&[Crypt::TlsEcc]), None);
+  }
+
+  #[test]
+  fn test_peer_id_prefix() {
+    use crate::templates::Handshake;
+    use crate::DEFAULT_PEER_ID_PREFIX;
+
+    let peer_id = Handshake::generate_peer_id("-MYCLNT-").unwrap();
+    assert!(peer_id.starts_with("-MYCLNT-"));
+    assert_eq!(peer_id.len(), 20);
+    assert_ne!(peer_id, Handshake::generate_peer_id("-MYCLNT-").unwrap());
+
+    let peer_id = Handshake::generate_peer_id(DEFAULT_PEER_ID_PREFIX).unwrap();
+    assert!(peer_id.starts_with(DEFAULT_PEER_ID_PREFIX));
+    assert_eq!(peer_id.len(), 20);
+
+    // Nothing random would be left of the peer_id
+    let peer_id = Handshake::generate_peer_id("-A-PREFIX-TOO-LONG-!");
+    assert!(matches!(peer_id, Err(crate::Error::InvalidHandshake(_))));
+    let peer_id = Handshake::generate_peer_id("-NINETEEN-BYTES-ID-").unwrap();
+    assert_eq!(peer_id.len(), 20);
   }
 
   #[test]
@@ -258,7 +1618,7 @@ mod tests {
 			"peer_id": ""
 		 }"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
   }
 
   #[test]
@@ -275,7 +1635,7 @@ mod tests {
 			}
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
   }
 
   #[test]
@@ -288,7 +1648,7 @@ mod tests {
 			"stream_bytes": 1234
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
   }
 
   #[test]
@@ -300,7 +1660,7 @@ mod tests {
 			"req_id": 0
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
   }
 
   #[test]
@@ -313,7 +1673,85 @@ mod tests {
 			"body": "Pong!"
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
+  }
+
+  #[test]
+  fn test_pong_unknown_fields() {
+    use crate::templates::PingResponse;
+
+    let msg = des(
+      r#"
+		{
+			"cmd": "response",
+			"to": 0,
+			"body": "Pong!",
+			"peer_id": "",
+			"crypt": null,
+			"added_in_a_later_version": [1, 2, 3]
+		}"#,
+    )
+    .unwrap();
+    let msg = rmpd(rmps(&msg));
+    let pong: PingResponse = msg.body().unwrap();
+    assert_eq!(pong.body, "Pong!");
+  }
+
+  #[test]
+  fn test_set_hashfield() {
+    use crate::templates::SetHashfield;
+    use serde_bytes::ByteBuf;
+
+    let body = SetHashfield {
+      site:          "1ADDR".to_string(),
+      hashfield_raw: ByteBuf::from(vec![1, 0, 0x39, 0x30]),
+    };
+    let msg = ZeroMessage::request("setHashfield", 0, body);
+    let body: SetHashfield = rmpd(rmp_serde::to_vec_named(&msg).unwrap()).body().unwrap();
+    assert_eq!(body.site, "1ADDR");
+    assert_eq!(body.hashfield_raw.as_ref(), &[1, 0, 0x39, 0x30]);
+  }
+
+  #[test]
+  fn test_decode_request_params() {
+    use super::{decode_request_params, Request, RequestType};
+
+    fn request(msg: ZeroMessage) -> Request {
+      match msg {
+        ZeroMessage::Request(req) => req,
+        ZeroMessage::Response(_) => panic!("expected a request"),
+      }
+    }
+
+    let msg = des(
+      r#"
+		{
+			"cmd": "announce",
+			"req_id": 1,
+			"params": {
+				"hashes": [],
+				"port": 15441,
+				"need_num": 20
+			}
+		}"#,
+    );
+    let req = request(msg.unwrap());
+    let decoded = decode_request_params(&req.cmd, &req).unwrap();
+    assert!(matches!(decoded, RequestType::Announce(announce) if announce.port == 15441));
+
+    let params = serde_json::json!({
+      "site": "1ADDR",
+      "inner_path": "content.json",
+      "location": 0,
+    });
+    let req = request(ZeroMessage::request("getFile", 2, params));
+    let decoded = decode_request_params("actionGetFile", &req).unwrap();
+    assert!(matches!(decoded, RequestType::GetFile(get) if get.inner_path == "content.json"));
+
+    let params = serde_json::json!({});
+    let req = request(ZeroMessage::request("someCommand", 3, params));
+    let decoded = decode_request_params(&req.cmd, &req).unwrap();
+    assert!(matches!(decoded, RequestType::Other(_)));
   }
 
   #[test]
@@ -328,7 +1766,7 @@ mod tests {
 			}
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
   }
 
   #[test]
@@ -342,6 +1780,26 @@ mod tests {
 			"peers_onion": []
 		}"#,
     );
-    assert_eq!(msg.is_ok(), true);
+    assert!(msg.is_ok());
+  }
+
+  #[cfg(feature = "tor")]
+  #[test]
+  fn test_pex_all_peers() {
+    use crate::templates::PexResponse;
+    use crate::PeerAddr;
+    use serde_bytes::ByteBuf;
+
+    let clearnet = PeerAddr::parse("1.2.3.4:15441").unwrap();
+    let onion = PeerAddr::parse("zp2ynpztyxj2kw7x.onion:15441").unwrap();
+    let body = PexResponse {
+      peers: vec![ByteBuf::from(clearnet.pack())],
+      peers_onion: vec![ByteBuf::from(onion.pack())],
+      ..Default::default()
+    };
+    let bytes = ZeroMessage::response(0, body).to_vec().unwrap();
+
+    let response: PexResponse = ZeroMessage::from_slice(&bytes).unwrap().body().unwrap();
+    assert_eq!(response.all_peers(), vec![clearnet, onion]);
   }
 }
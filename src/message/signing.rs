@@ -0,0 +1,93 @@
+//! Hooks for signing and verifying messages, without
+//! tying the crate to any particular crypto library.
+//!
+//! Signatures are made over a message's canonical bytes, which
+//! are the same as `ZeroMessage::to_vec`. The encoding is
+//! deterministic for a given message type: maps are sorted, struct
+//! fields keep their declaration order. Keys are not sorted overall,
+//! so a relay that decodes and re-encodes a message as the same type
+//! verifies the same bytes the sender signed, but a verifier written
+//! elsewhere must reproduce this encoding rather than sort keys.
+
+use super::ZeroMessage;
+use crate::error::Error;
+
+/// Produces a signature over a message's canonical bytes.
+pub trait MessageSigner {
+  fn sign(&self, bytes: &[u8]) -> Vec<u8>;
+}
+
+/// Checks a signature made by a `MessageSigner`.
+pub trait MessageVerifier {
+  fn verify(&self, bytes: &[u8], signature: &[u8]) -> bool;
+}
+
+impl ZeroMessage {
+  /// The bytes a signature over this message is made over.
+  pub fn canonical_bytes(&self) -> Result<Vec<u8>, Error> {
+    self.to_vec()
+  }
+  /// Sign the message's canonical bytes.
+  pub fn sign<S: MessageSigner + ?Sized>(&self, signer: &S) -> Result<Vec<u8>, Error> {
+    Ok(signer.sign(&self.canonical_bytes()?))
+  }
+  /// Verify a signature over the message's canonical bytes,
+  /// e.g. before a relay forwards it.
+  pub fn verify<V: MessageVerifier + ?Sized>(
+    &self,
+    verifier: &V,
+    signature: &[u8],
+  ) -> Result<(), Error> {
+    if verifier.verify(&self.canonical_bytes()?, signature) {
+      Ok(())
+    } else {
+      Err(Error::InvalidSignature)
+    }
+  }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, ignore)]
+mod tests {
+  use super::{MessageSigner, MessageVerifier};
+  use crate::error::Error;
+  use crate::message::ZeroMessage;
+
+  /// Not a real signature: xors a checksum of the bytes with a key.
+  struct Stub(u8);
+
+  impl Stub {
+    fn digest(&self, bytes: &[u8]) -> Vec<u8> {
+      let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+      vec![sum ^ self.0, bytes.len() as u8]
+    }
+  }
+
+  impl MessageSigner for Stub {
+    fn sign(&self, bytes: &[u8]) -> Vec<u8> {
+      self.digest(bytes)
+    }
+  }
+
+  impl MessageVerifier for Stub {
+    fn verify(&self, bytes: &[u8], signature: &[u8]) -> bool {
+      self.digest(bytes) == signature
+    }
+  }
+
+  #[test]
+  fn test_sign_and_verify() {
+    let body = serde_json::json!({"site": "1Site", "inner_path": "content.json", "location": 0});
+    let message = ZeroMessage::request("getFile", 3, body);
+    let signature = message.sign(&Stub(7)).unwrap();
+
+    // A relay decodes and re-encodes the message before forwarding it.
+    let relayed = ZeroMessage::from_slice(&message.to_vec().unwrap()).unwrap();
+    assert!(relayed.verify(&Stub(7), &signature).is_ok());
+
+    let result = relayed.verify(&Stub(8), &signature);
+    assert!(matches!(result, Err(Error::InvalidSignature)));
+    let other = ZeroMessage::request("getFile", 4, serde_json::json!({"site": "1Site"}));
+    assert!(other.verify(&Stub(7), &signature).is_err());
+  }
+}
@@ -1,12 +1,65 @@
+use crate::address::{check_onion, AddrFamily, ParseError};
 use crate::util::is_default;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_bytes::ByteBuf;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+/// Prefix of the peer_id generated by `Handshake::generate_peer_id`,
+/// identifying the client to its peers.
+pub const DEFAULT_PEER_ID_PREFIX: &str = "-ZN0070-";
+
+const PEER_ID_LENGTH: usize = 20;
+const PEER_ID_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// An encryption a peer supports, serialized by the name used
+/// in `crypt` and `crypt_supported`. Unknown names are kept in `Other`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Crypt {
+  TlsRsa,
+  TlsEcc,
+  Other(String),
+}
+
+impl Crypt {
+  pub fn as_str(&self) -> &str {
+    match self {
+      Crypt::TlsRsa => "tls-rsa",
+      Crypt::TlsEcc => "tls-ecc",
+      Crypt::Other(other) => other,
+    }
+  }
+}
+
+impl From<&str> for Crypt {
+  fn from(value: &str) -> Crypt {
+    match value {
+      "tls-rsa" => Crypt::TlsRsa,
+      "tls-ecc" => Crypt::TlsEcc,
+      _ => Crypt::Other(value.to_string()),
+    }
+  }
+}
+
+impl Serialize for Crypt {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(self.as_str())
+  }
+}
+
+impl<'de> Deserialize<'de> for Crypt {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Crypt, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(Crypt::from(value.as_str()))
+  }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 #[serde(default)]
 pub struct Handshake {
   /// The PeerID of the sender
@@ -34,14 +87,31 @@ pub struct Handshake {
   pub target_address:  Option<String>,
   #[serde(default, skip_serializing_if = "is_default")]
   pub version:         String,
+  /// Hash ids of the optional files the sender has, piggybacked
+  /// by some peers to save a `getHashfield` round-trip
+  #[serde(default, skip_serializing_if = "is_default")]
+  pub hashfield_raw:   Option<ByteBuf>,
 }
 
 impl Handshake {
   pub fn new() -> Handshake {
+    Handshake::new_with_version("0.7", 4486)
+  }
+
+  /// Start building a handshake from the defaults of `new`.
+  pub fn builder() -> HandshakeBuilder {
+    HandshakeBuilder {
+      handshake: Handshake::new(),
+    }
+  }
+
+  /// Create a handshake announcing the given client version and revision,
+  /// e.g. to match a specific peer version when testing interoperability.
+  pub fn new_with_version(version: &str, rev: usize) -> Handshake {
     let now = SystemTime::now();
     Handshake {
-      version:         "0.7".to_string(),
-      rev:             4486,
+      version:         version.to_string(),
+      rev,
       protocol:        "v2".to_string(),
       use_bin_type:    true,
       fileserver_port: 0,
@@ -53,7 +123,187 @@ impl Handshake {
       crypt:          None,
       target_address: None,
       peer_id:        String::new(),
+      hashfield_raw:  None,
+    }
+  }
+
+  /// `target_address` as a `PeerAddr`, `None` if it is missing or
+  /// cannot be parsed. Peers usually send it without a port, the
+  /// port is 0 then.
+  pub fn target_peer_addr(&self) -> Option<crate::PeerAddr> {
+    let target = self.target_address.as_deref()?;
+    if let Ok(ip) = target.parse::<IpAddr>() {
+      return Some(crate::PeerAddr::from(SocketAddr::new(ip, 0)));
     }
+    crate::PeerAddr::parse(target)
+      .or_else(|_| crate::PeerAddr::parse(format!("{}:0", target)))
+      .ok()
+  }
+
+  /// The hash ids packed in `hashfield_raw`, empty if none were sent.
+  pub fn hashfield(&self) -> Vec<u16> {
+    match &self.hashfield_raw {
+      Some(raw) => raw
+        .chunks_exact(2)
+        .map(|id| u16::from_le_bytes([id[0], id[1]]))
+        .collect(),
+      None => vec![],
+    }
+  }
+
+  /// The encryption to use with the peer that sent this handshake,
+  /// given the ciphers we support in order of preference, or `None`
+  /// to stay in plaintext. A `crypt` chosen by the peer is honored
+  /// if we support it, otherwise the first of `mine` the peer also
+  /// supports is picked.
+  pub fn negotiated_crypt(&self, mine: &[String]) -> Option<String> {
+    if let Some(crypt) = &self.crypt {
+      return mine.iter().find(|supported| *supported == crypt).cloned();
+    }
+    mine
+      .iter()
+      .find(|supported| self.crypt_supported.contains(supported))
+      .cloned()
+  }
+
+  /// The encryptions the sender supports, see `crypt_supported`.
+  pub fn crypts(&self) -> Vec<Crypt> {
+    self
+      .crypt_supported
+      .iter()
+      .map(|crypt| Crypt::from(crypt.as_str()))
+      .collect()
+  }
+
+  /// Like `negotiated_crypt`, with typed ciphers.
+  pub fn negotiate(&self, mine: &[Crypt]) -> Option<Crypt> {
+    let mine: Vec<String> = mine
+      .iter()
+      .map(|crypt| crypt.as_str().to_string())
+      .collect();
+    self
+      .negotiated_crypt(&mine)
+      .map(|crypt| Crypt::from(crypt.as_str()))
+  }
+
+  /// Check that this handshake is a sane answer to `request`: both
+  /// must speak the same protocol, and a peer reached through its
+  /// onion address must announce that onion.
+  pub fn validate_response(&self, request: &Handshake) -> Result<(), HandshakeError> {
+    if !request.protocol.is_empty() && self.protocol != request.protocol {
+      return Err(HandshakeError::ProtocolMismatch {
+        expected: request.protocol.clone(),
+        found:    self.protocol.clone(),
+      });
+    }
+    let target = request.target_address.as_deref().unwrap_or("");
+    let host = target.split(':').next().unwrap_or(target);
+    if let Some(onion) = host.strip_suffix(".onion") {
+      if self.onion.as_deref() != Some(onion) {
+        return Err(HandshakeError::AddressMismatch {
+          expected: onion.to_string(),
+          found:    self.onion.clone(),
+        });
+      }
+    }
+    Ok(())
+  }
+
+  /// Generate a random 20 character peer_id starting with `prefix`,
+  /// e.g. `"-MYCLNT-"`, use `DEFAULT_PEER_ID_PREFIX` to identify
+  /// as this crate. Fails if the prefix leaves no room for at
+  /// least one random character.
+  pub fn generate_peer_id(prefix: &str) -> Result<String, crate::Error> {
+    if prefix.len() >= PEER_ID_LENGTH {
+      return Err(crate::Error::InvalidHandshake(format!(
+        "peer_id prefix `{}` is not shorter than {} bytes",
+        prefix, PEER_ID_LENGTH
+      )));
+    }
+    let mut peer_id = prefix.to_string();
+    let mut random = 0u64;
+    while peer_id.len() < PEER_ID_LENGTH {
+      if random == 0 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(peer_id.len());
+        random = hasher.finish();
+      }
+      let index = (random % PEER_ID_CHARS.len() as u64) as usize;
+      peer_id.push(PEER_ID_CHARS[index] as char);
+      random /= PEER_ID_CHARS.len() as u64;
+    }
+    Ok(peer_id)
+  }
+}
+
+/// Why a handshake response was rejected by `Handshake::validate_response`.
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+  #[error("Peer speaks protocol `{found}`, expected `{expected}`")]
+  ProtocolMismatch { expected: String, found: String },
+  #[error("Peer announced onion {found:?}, expected `{expected}`")]
+  AddressMismatch {
+    expected: String,
+    found:    Option<String>,
+  },
+}
+
+/// Chainable setters for a `Handshake`, see `Handshake::builder`.
+/// ```
+/// use zeronet_protocol::templates::Handshake;
+///
+/// let handshake = Handshake::builder()
+///   .fileserver_port(15441)
+///   .crypt_supported(&["tls-rsa"])
+///   .build()
+///   .unwrap();
+/// assert_eq!(handshake.fileserver_port, 15441);
+/// ```
+#[derive(Debug, Clone)]
+pub struct HandshakeBuilder {
+  handshake: Handshake,
+}
+
+impl HandshakeBuilder {
+  pub fn peer_id(mut self, peer_id: &str) -> Self {
+    self.handshake.peer_id = peer_id.to_string();
+    self
+  }
+  pub fn onion(mut self, onion: &str) -> Self {
+    self.handshake.onion = Some(onion.to_string());
+    self
+  }
+  pub fn fileserver_port(mut self, port: usize) -> Self {
+    self.handshake.fileserver_port = port;
+    self
+  }
+  pub fn port_opened(mut self, port_opened: bool) -> Self {
+    self.handshake.port_opened = Some(port_opened);
+    self
+  }
+  pub fn crypt_supported(mut self, crypts: &[&str]) -> Self {
+    self.handshake.crypt_supported = crypts.iter().map(|crypt| crypt.to_string()).collect();
+    self
+  }
+  pub fn target_address(mut self, address: &str) -> Self {
+    self.handshake.target_address = Some(address.to_string());
+    self
+  }
+  pub fn version(mut self, version: &str, rev: usize) -> Self {
+    self.handshake.version = version.to_string();
+    self.handshake.rev = rev;
+    self
+  }
+
+  /// Finish the handshake. Onion peers stay anonymous, so
+  /// setting a clearnet `peer_id` along with `onion` is rejected.
+  pub fn build(self) -> Result<Handshake, crate::Error> {
+    if self.handshake.onion.is_some() && !self.handshake.peer_id.is_empty() {
+      return Err(crate::Error::InvalidHandshake(
+        "onion peers must not send a peer_id".to_string(),
+      ));
+    }
+    Ok(self.handshake)
   }
 }
 
@@ -67,13 +317,21 @@ pub struct PingResponse {
 pub struct Announce {
   pub port:            usize,
   #[serde(default, skip_serializing_if = "is_default")]
-  pub add:             Vec<String>,
-  #[serde(default, skip_serializing_if = "is_default")]
-  pub need_types:      Vec<String>,
+  pub add:             Vec<TransportType>,
   #[serde(default, skip_serializing_if = "is_default")]
-  pub need_num:        usize,
+  pub need_types:      Vec<TransportType>,
+  /// Sent whenever set, even to zero, since trackers may
+  /// treat a missing `need_num` as their own default
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub need_num:        Option<usize>,
   #[serde(default, skip_serializing_if = "is_default")]
   pub hashes:          Vec<ByteBuf>,
+  /// Further 32 byte hashes concatenated into one byte string,
+  /// which saves the per-hash overhead, see `pack_hashes`.
+  /// A non-standard extension, unset unless asked for, ZeroNet
+  /// trackers only read `hashes`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub hashes_packed:   Option<ByteBuf>,
   #[serde(default, skip_serializing_if = "is_default")]
   pub onions:          Vec<String>,
   #[serde(default, skip_serializing_if = "is_default")]
@@ -84,12 +342,149 @@ pub struct Announce {
   pub delete:          bool,
 }
 
+impl Announce {
+  /// Add one of our onion addresses to `onions`, with or without
+  /// the ".onion" suffix. Only well-formed v2 (16 characters) or
+  /// v3 (56 characters) base32 addresses are accepted, as by
+  /// `PeerAddr::parse`.
+  pub fn add_onion(&mut self, onion: &str) -> Result<(), ParseError> {
+    let onion = onion.strip_suffix(".onion").unwrap_or(onion);
+    check_onion(onion)?;
+    self.onions.push(onion.to_string());
+    Ok(())
+  }
+
+  /// Turn the announce into a request to stop tracking us for
+  /// the sites in `hashes`, sent when we stop seeding them.
+  /// Trackers still answer with an `AnnounceResponse`, usually
+  /// without peers.
+  pub fn delete_sites(&mut self, hashes: Vec<ByteBuf>) {
+    self.hashes = hashes;
+    self.delete = true;
+  }
+
+  /// Move `hashes` into `hashes_packed`, only for trackers known
+  /// to accept the compact form, others ignore packed hashes.
+  /// Fails without moving any if one is not 32 bytes long.
+  pub fn pack_hashes(&mut self) -> Result<(), crate::Error> {
+    if let Some(hash) = self.hashes.iter().find(|hash| hash.len() != 32) {
+      return Err(crate::Error::InvalidHashLength(hash.len()));
+    }
+    let mut packed = self.hashes_packed.take().map(ByteBuf::into_vec).unwrap_or_default();
+    for hash in self.hashes.drain(..) {
+      packed.extend_from_slice(&hash);
+    }
+    self.hashes_packed = Some(ByteBuf::from(packed));
+    Ok(())
+  }
+
+  /// Every announced hash, from both `hashes` and `hashes_packed`.
+  /// Fails if `hashes_packed` does not split into 32 byte hashes.
+  pub fn all_hashes(&self) -> Result<Vec<ByteBuf>, crate::Error> {
+    let mut hashes = self.hashes.clone();
+    if let Some(packed) = &self.hashes_packed {
+      if packed.len() % 32 != 0 {
+        return Err(crate::Error::InvalidHashLength(packed.len() % 32));
+      }
+      hashes.extend(packed.chunks(32).map(|hash| ByteBuf::from(hash.to_vec())));
+    }
+    Ok(hashes)
+  }
+}
+
+/// Transport type as used in the `add` and `need_types` fields
+/// of an announce, serialized as `"ipv4"`, `"ipv6"` or `"onion"`.
+/// Unrecognized values are kept in `Other` for forward compatibility.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TransportType {
+  IPV4,
+  IPV6,
+  Onion,
+  Other(String),
+}
+
+impl TransportType {
+  pub fn as_str(&self) -> &str {
+    match self {
+      TransportType::IPV4 => "ipv4",
+      TransportType::IPV6 => "ipv6",
+      TransportType::Onion => "onion",
+      TransportType::Other(other) => other,
+    }
+  }
+}
+
+impl From<&str> for TransportType {
+  fn from(value: &str) -> TransportType {
+    match value {
+      "ipv4" => TransportType::IPV4,
+      "ipv6" => TransportType::IPV6,
+      "onion" => TransportType::Onion,
+      other => TransportType::Other(other.to_string()),
+    }
+  }
+}
+
+impl Serialize for TransportType {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(self.as_str())
+  }
+}
+
+impl<'de> Deserialize<'de> for TransportType {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TransportType, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(TransportType::from(value.as_str()))
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
 pub struct AnnounceResponse {
+  /// Some trackers send a flat list of packed addresses instead,
+  /// it decodes as a single `AnnouncePeers`, see `from_flat`.
+  #[serde(deserialize_with = "deserialize_announce_peers")]
   pub peers: Vec<AnnouncePeers>,
 }
 
+fn deserialize_announce_peers<'de, D>(deserializer: D) -> Result<Vec<AnnouncePeers>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum PeersRepr {
+    Structured(Vec<AnnouncePeers>),
+    Flat(Vec<ByteBuf>),
+  }
+  match PeersRepr::deserialize(deserializer)? {
+    PeersRepr::Structured(peers) => Ok(peers),
+    PeersRepr::Flat(peers) => Ok(vec![AnnouncePeers::from_flat(peers)]),
+  }
+}
+
+impl AnnounceResponse {
+  /// The peers of one transport family, over all announced sites.
+  /// Peers of transports that are not enabled cannot be unpacked
+  /// and are skipped.
+  pub fn peers_of(&self, family: AddrFamily) -> Vec<crate::PeerAddr> {
+    self
+      .peers
+      .iter()
+      .flat_map(|peers| match family {
+        AddrFamily::IPV4 => vec![&peers.ip_v4],
+        AddrFamily::IPV6 => vec![&peers.ip_v6],
+        AddrFamily::Onion => vec![&peers.onion_v2, &peers.onion_v3],
+        AddrFamily::I2P => vec![&peers.i2p_b32],
+        AddrFamily::Loki => vec![&peers.loki],
+      })
+      .flatten()
+      .filter_map(|bytes| crate::PeerAddr::unpack(bytes).ok())
+      .filter(|address| address.is_family(family))
+      .collect()
+  }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct AnnouncePeers {
@@ -108,6 +503,24 @@ pub struct AnnouncePeers {
   pub loki:     Vec<ByteBuf>,
 }
 
+impl AnnouncePeers {
+  /// Sort a flat list of packed addresses into their transports
+  /// by length, anything unrecognized is taken to be IPv4.
+  pub fn from_flat(packed: Vec<ByteBuf>) -> AnnouncePeers {
+    let mut peers = AnnouncePeers::default();
+    for address in packed {
+      match address.len() {
+        18 => peers.ip_v6.push(address),
+        12 => peers.onion_v2.push(address),
+        37 => peers.onion_v3.push(address),
+        34 => peers.i2p_b32.push(address),
+        _ => peers.ip_v4.push(address),
+      }
+    }
+    peers
+  }
+}
+
 impl Debug for AnnouncePeers {
   fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
     let iterator = self
@@ -130,29 +543,87 @@ pub struct ErrorResponse {
   pub error: String,
 }
 
+/// The `{ ok }` body shared by `update`, `setHashfield` and
+/// `setPieceFields` responses. Some peers send a message such as
+/// `"Thanks, file content.json updated!"` instead of `true`, any
+/// non-empty message decodes as `true`.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct OkResponse {
-  pub ok: String,
+  #[serde(deserialize_with = "deserialize_ok")]
+  pub ok: bool,
+}
+
+fn deserialize_ok<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum OkRepr {
+    Flag(bool),
+    Message(String),
+  }
+  match OkRepr::deserialize(deserializer)? {
+    OkRepr::Flag(ok) => Ok(ok),
+    OkRepr::Message(message) => Ok(!message.is_empty()),
+  }
+}
+
+/// Replace backslashes in `inner_path` with the forward slashes
+/// peers expect, paths are always normalized when serialized.
+/// ```
+/// use zeronet_protocol::templates::normalize_inner_path;
+///
+/// assert_eq!(normalize_inner_path("data\\users\\content.json"), "data/users/content.json");
+/// ```
+pub fn normalize_inner_path(inner_path: &str) -> String {
+  inner_path.replace('\\', "/")
+}
+
+fn serialize_inner_path<S: Serializer>(inner_path: &str, serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.serialize_str(&normalize_inner_path(inner_path))
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct GetFile {
   pub site:       String,
+  #[serde(serialize_with = "serialize_inner_path")]
   pub inner_path: String,
   pub location:   usize,
   #[serde(skip_serializing_if = "is_default")]
   pub file_size:  usize,
 }
 
+impl GetFile {
+  /// The requests downloading a file of `file_size` bytes in order,
+  /// each starting `chunk_size` bytes after the previous one.
+  /// An empty file needs none. Panics if `chunk_size` is 0.
+  pub fn chunks(site: &str, inner_path: &str, file_size: usize, chunk_size: usize) -> Vec<GetFile> {
+    assert!(chunk_size > 0, "chunk_size must not be 0");
+    (0..file_size)
+      .step_by(chunk_size)
+      .map(|location| GetFile {
+        site: site.to_string(),
+        inner_path: normalize_inner_path(inner_path),
+        location,
+        file_size,
+      })
+      .collect()
+  }
+}
+
+/// Some peers leave out `location` or `size`, both default to 0.
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct GetFileResponse {
   pub body:     ByteBuf,
+  #[serde(default)]
   pub location: usize,
+  #[serde(default)]
   pub size:     usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct StreamFile {
+  pub site:       String,
+  #[serde(serialize_with = "serialize_inner_path")]
   pub inner_path: String,
   pub size:       usize,
 }
@@ -162,25 +633,49 @@ pub struct StreamFileResponse {
   pub stream_bytes: usize,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct Pex {
   pub site:        String,
   pub peers:       Vec<ByteBuf>,
+  #[serde(skip_serializing_if = "is_default")]
   pub peers_onion: Option<Vec<ByteBuf>>,
+  #[serde(skip_serializing_if = "is_default")]
   pub peers_ipv6:  Option<Vec<ByteBuf>>,
   pub need:        usize,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct PexResponse {
   pub peers:       Vec<ByteBuf>,
+  #[serde(skip_serializing_if = "is_default")]
+  pub peers_ipv6:  Vec<ByteBuf>,
   pub peers_onion: Vec<ByteBuf>,
 }
 
+impl PexResponse {
+  /// Every peer in the response, clearnet first, then onions.
+  /// Peers of transports that are not enabled cannot be unpacked
+  /// and are skipped.
+  pub fn all_peers(&self) -> Vec<crate::PeerAddr> {
+    self
+      .peers
+      .iter()
+      .chain(&self.peers_ipv6)
+      .chain(&self.peers_onion)
+      .filter_map(|bytes| crate::PeerAddr::unpack(bytes).ok())
+      .collect()
+  }
+}
+
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct UpdateFile {
   pub site:       String,
+  #[serde(serialize_with = "serialize_inner_path")]
   pub inner_path: String,
   pub body:       String,
-  pub diffs:      HashMap<String, Vec<Value>>,
+  pub diffs:      BTreeMap<String, Vec<Value>>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -190,10 +685,7 @@ pub struct Diff {
   pub diff:   String,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct UpdateFileResponse {
-  pub ok: String,
-}
+pub type UpdateFileResponse = OkResponse;
 
 pub struct ListModified {
   pub site:  String,
@@ -201,25 +693,30 @@ pub struct ListModified {
 }
 
 pub struct ListModifiedResponse {
-  pub modified_files: HashMap<String, usize>,
+  pub modified_files: BTreeMap<String, usize>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct GetHashfield {
   pub site: String,
 }
 
+/// `hashfield_raw` holds the hash ids as packed uint16s, sent
+/// uncompressed as ZeroNet does in both `getHashfield` responses
+/// and `setHashfield` requests.
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct GetHashfieldResponse {
   pub hashfield_raw: ByteBuf,
 }
 
+/// See `GetHashfieldResponse` for the encoding of `hashfield_raw`.
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SetHashfield {
   pub site:          String,
   pub hashfield_raw: ByteBuf,
 }
 
-pub struct SetHashfieldResponse {
-  pub ok: bool,
-}
+pub type SetHashfieldResponse = OkResponse;
 
 pub struct FindHashIds {
   pub site:     String,
@@ -227,19 +724,29 @@ pub struct FindHashIds {
 }
 
 pub struct FindHashIdsResponse {
-  pub peers:       HashMap<usize, Vec<ByteBuf>>,
-  pub peers_onion: HashMap<usize, Vec<ByteBuf>>,
+  pub peers:       BTreeMap<usize, Vec<ByteBuf>>,
+  pub peers_onion: BTreeMap<usize, Vec<ByteBuf>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Checkport {
   pub port: u16,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct CheckportResponse {
   pub status:      String,
   pub ip_external: String,
 }
 
+impl CheckportResponse {
+  /// Whether the peer reported the port as open.
+  pub fn is_open(&self) -> bool {
+    self.status == "open"
+  }
+}
+
 pub struct GetPieceFields {
   pub site: String,
 }
@@ -255,9 +762,7 @@ pub struct SetPieceFields {
   pub piecefields_packed: ByteBuf,
 }
 
-pub struct SetPieceFieldsResponse {
-  pub ok: bool,
-}
+pub type SetPieceFieldsResponse = OkResponse;
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(default)]
@@ -1,25 +1,176 @@
-use serde::{Deserialize, Serialize};
+use crate::util::join_path;
+use serde::de::{Error, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_bytes::ByteBuf;
 use serde_json::Number;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt::Formatter;
 
 /// Value is a custom enum mimicking serde_json::Value
 /// but with serde_bytes::ByteBuf added in, this way
 /// we can deserialize the parameters correctly.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+///
+/// Objects are stored in a BTreeMap so their keys are always
+/// encoded in the same (sorted) order. Encoding the same message
+/// twice therefore yields identical bytes, which is required
+/// when verifying signatures over re-serialized payloads.
+///
+/// Binary data that is valid UTF-8 decodes as a `String`, and
+/// sequences of bytes as `Bytes`, but an empty sequence stays an
+/// empty `Array` so empty lists survive being re-encoded.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum Value {
+  #[default]
   Null,
   Bool(bool),
   Number(Number),
   String(String),
   Bytes(ByteBuf),
   Array(Vec<Value>),
-  Object(HashMap<String, Value>),
+  Object(BTreeMap<String, Value>),
 }
 
-impl Default for Value {
-  fn default() -> Self {
-    Value::Null
+impl<'de> Deserialize<'de> for Value {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+    deserializer.deserialize_any(ValueVisitor)
+  }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+  type Value = Value;
+
+  fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+    formatter.write_str("any value")
+  }
+
+  fn visit_unit<E: Error>(self) -> Result<Value, E> {
+    Ok(Value::Null)
+  }
+  fn visit_none<E: Error>(self) -> Result<Value, E> {
+    Ok(Value::Null)
+  }
+  fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+    Value::deserialize(deserializer)
+  }
+  fn visit_bool<E: Error>(self, value: bool) -> Result<Value, E> {
+    Ok(Value::Bool(value))
+  }
+  fn visit_i64<E: Error>(self, value: i64) -> Result<Value, E> {
+    Ok(Value::Number(value.into()))
+  }
+  fn visit_u64<E: Error>(self, value: u64) -> Result<Value, E> {
+    Ok(Value::Number(value.into()))
+  }
+  fn visit_f64<E: Error>(self, value: f64) -> Result<Value, E> {
+    Ok(Number::from_f64(value).map_or(Value::Null, Value::Number))
+  }
+  fn visit_str<E: Error>(self, value: &str) -> Result<Value, E> {
+    Ok(Value::String(value.to_string()))
+  }
+  fn visit_string<E: Error>(self, value: String) -> Result<Value, E> {
+    Ok(Value::String(value))
+  }
+  fn visit_bytes<E: Error>(self, value: &[u8]) -> Result<Value, E> {
+    self.visit_byte_buf(value.to_vec())
+  }
+  fn visit_byte_buf<E: Error>(self, value: Vec<u8>) -> Result<Value, E> {
+    match String::from_utf8(value) {
+      Ok(string) => Ok(Value::String(string)),
+      Err(err) => Ok(Value::Bytes(ByteBuf::from(err.into_bytes()))),
+    }
+  }
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+    let mut values = vec![];
+    while let Some(value) = seq.next_element()? {
+      values.push(value);
+    }
+    let byte = |value: &Value| match value {
+      Value::Number(number) => number.as_u64().filter(|n| *n <= 255).map(|n| n as u8),
+      _ => None,
+    };
+    if values.is_empty() {
+      return Ok(Value::Array(values));
+    }
+    match values.iter().map(byte).collect::<Option<Vec<u8>>>() {
+      Some(bytes) => Ok(Value::Bytes(ByteBuf::from(bytes))),
+      None => Ok(Value::Array(values)),
+    }
+  }
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+    let mut values = BTreeMap::new();
+    while let Some((key, value)) = map.next_entry()? {
+      values.insert(key, value);
+    }
+    Ok(Value::Object(values))
+  }
+}
+
+impl Value {
+  /// The paths of the `Bytes` that are not valid UTF-8, these were
+  /// sent as either a string with invalid UTF-8 or binary data.
+  pub(crate) fn invalid_utf8_fields(&self, path: &str) -> Vec<String> {
+    match self {
+      Value::Bytes(bytes) if std::str::from_utf8(bytes).is_err() => vec![path.to_string()],
+      Value::Array(items) => items
+        .iter()
+        .enumerate()
+        .flat_map(|(index, item)| item.invalid_utf8_fields(&join_path(path, &index.to_string())))
+        .collect(),
+      Value::Object(map) => map
+        .iter()
+        .flat_map(|(key, item)| item.invalid_utf8_fields(&join_path(path, key)))
+        .collect(),
+      _ => vec![],
+    }
+  }
+
+  /// A copy with the `Bytes` at `fields` replaced by their lossy
+  /// conversion to a string.
+  pub(crate) fn with_lossy_utf8(&self, fields: &[String], path: &str) -> Value {
+    match self {
+      Value::Bytes(bytes) if fields.iter().any(|field| field == path) => {
+        Value::String(String::from_utf8_lossy(bytes).into_owned())
+      }
+      Value::Array(items) => Value::Array(
+        items
+          .iter()
+          .enumerate()
+          .map(|(index, item)| item.with_lossy_utf8(fields, &join_path(path, &index.to_string())))
+          .collect(),
+      ),
+      Value::Object(map) => Value::Object(
+        map
+          .iter()
+          .map(|(key, item)| {
+            let item = item.with_lossy_utf8(fields, &join_path(path, key));
+            (key.clone(), item)
+          })
+          .collect(),
+      ),
+      value => value.clone(),
+    }
+  }
+}
+
+/// Bytes become base64 strings, since JSON has no binary type.
+impl From<Value> for serde_json::Value {
+  fn from(value: Value) -> serde_json::Value {
+    match value {
+      Value::Null => serde_json::Value::Null,
+      Value::Bool(bool) => serde_json::Value::Bool(bool),
+      Value::Number(number) => serde_json::Value::Number(number),
+      Value::String(string) => serde_json::Value::String(string),
+      Value::Bytes(bytes) => serde_json::Value::String(base64::encode(bytes)),
+      Value::Array(array) => serde_json::Value::Array(array.into_iter().map(Into::into).collect()),
+      Value::Object(map) => serde_json::Value::Object(
+        map
+          .into_iter()
+          .map(|(key, value)| (key, value.into()))
+          .collect(),
+      ),
+    }
   }
 }
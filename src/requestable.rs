@@ -12,23 +12,26 @@ pub trait Requestable {
   /// Returns the ID of the request responded to.
   fn to(&self) -> Option<Self::Key>;
 
+  /// Returns the number of raw bytes that follow the message
+  /// on the wire, if it announces any.
+  fn stream_bytes(&self) -> Option<usize> {
+    None
+  }
+
+  /// Attaches the raw bytes that were read after the message.
+  fn set_stream(&mut self, _stream: Vec<u8>) {}
+
   /// If the message has a request ID, it is a request.
   /// It is possible for a response to simultaneously be
   /// a request.
   fn is_request(&self) -> bool {
-    match self.req_id() {
-      Some(_) => true,
-      None => false,
-    }
+    self.req_id().is_some()
   }
 
   /// If the message has a response ID, it is a response.
   /// It is possible for a response to simultaneously be
   /// a request.
   fn is_response(&self) -> bool {
-    match self.to() {
-      Some(_) => true,
-      None => false,
-    }
+    self.to().is_some()
   }
 }
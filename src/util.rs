@@ -1,5 +1,319 @@
+use crate::error::Error;
+use futures::channel::oneshot;
+use rmp::Marker;
+use rmp_serde::decode::Error as DecodeError;
+use std::cell::Cell;
 use std::default::Default;
+use std::future::Future;
+use std::time::Duration;
+
+thread_local! {
+  static KEEP_DEFAULTS: Cell<bool> = const { Cell::new(false) };
+}
 
 pub fn is_default<T: Default + PartialEq>(t: &T) -> bool {
-  t == &T::default()
+  !KEEP_DEFAULTS.with(Cell::get) && t == &T::default()
+}
+
+/// Run `f` with `is_default` always returning false, so that
+/// anything serialized inside it keeps its default fields.
+pub fn with_defaults<R>(f: impl FnOnce() -> R) -> R {
+  let previous = KEEP_DEFAULTS.with(|keep| keep.replace(true));
+  let result = f();
+  KEEP_DEFAULTS.with(|keep| keep.set(previous));
+  result
+}
+
+/// A future resolving once `duration` has passed, timed on its
+/// own thread so it works with any executor.
+pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
+  let (tx, rx) = oneshot::channel();
+  std::thread::spawn(move || {
+    std::thread::sleep(duration);
+    let _ = tx.send(());
+  });
+  async {
+    let _ = rx.await;
+  }
+}
+
+/// The most items an array or map in a received message may
+/// declare by default, see `frame_len_with_limit`.
+pub const DEFAULT_MAX_ITEMS: usize = 1 << 16;
+
+/// The most bytes a string, binary or extension value in a received
+/// message may declare by default, see `frame_len_with_limit`.
+pub const DEFAULT_MAX_LEN: usize = 1 << 24;
+
+/// The length of the first complete MessagePack value in `bytes`,
+/// or `None` if more bytes are needed to complete it. Arrays and
+/// maps may hold up to `DEFAULT_MAX_ITEMS` items, other values up
+/// to `DEFAULT_MAX_LEN` bytes.
+/// ```
+/// use zeronet_protocol::util::frame_len;
+///
+/// let bytes = rmp_serde::to_vec(&("ping", 1)).unwrap();
+/// assert_eq!(frame_len(&bytes).unwrap(), Some(bytes.len()));
+/// assert_eq!(frame_len(&bytes[..3]).unwrap(), None);
+/// ```
+pub fn frame_len(bytes: &[u8]) -> Result<Option<usize>, Error> {
+  frame_len_with_limit(bytes, DEFAULT_MAX_ITEMS, DEFAULT_MAX_LEN)
+}
+
+/// Like `frame_len`, failing with `MessageTooLarge` as soon as an
+/// array or map declares more than `max_items` items, or a string,
+/// binary or extension value more than `max_len` bytes, rather
+/// than waiting for a peer to send them.
+pub fn frame_len_with_limit(
+  bytes: &[u8],
+  max_items: usize,
+  max_len: usize,
+) -> Result<Option<usize>, Error> {
+  scan_frame(bytes, &mut FrameScan::default(), max_items, max_len)
+}
+
+/// How far `scan_frame` got through an incomplete value.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameScan {
+  // Number of values left to skip, arrays and maps add their items
+  remaining: usize,
+  // Where the next value starts
+  position:  usize,
+}
+
+impl Default for FrameScan {
+  fn default() -> FrameScan {
+    FrameScan {
+      remaining: 1,
+      position:  0,
+    }
+  }
+}
+
+/// Like `frame_len_with_limit`, but resuming from `scan` and leaving
+/// it at the last complete value when more bytes are needed, so the
+/// same bytes are not scanned again as a large value arrives. `scan`
+/// must be reset whenever bytes are taken from the front of `bytes`.
+pub(crate) fn scan_frame(
+  bytes: &[u8],
+  scan: &mut FrameScan,
+  max_items: usize,
+  max_len: usize,
+) -> Result<Option<usize>, Error> {
+  let FrameScan {
+    mut remaining,
+    mut position,
+  } = *scan;
+  while remaining > 0 {
+    *scan = FrameScan {
+      remaining,
+      position,
+    };
+    let marker = match bytes.get(position) {
+      Some(byte) => Marker::from_u8(*byte),
+      None => return Ok(None),
+    };
+    position += 1;
+    remaining -= 1;
+
+    // Bytes following the marker that hold the length, and the
+    // number of bytes to skip on top of the encoded length
+    let (length_bytes, extra) = match marker {
+      Marker::FixPos(_) | Marker::FixNeg(_) | Marker::Null | Marker::True | Marker::False => {
+        (0, 0)
+      }
+      Marker::U8 | Marker::I8 => (0, 1),
+      Marker::U16 | Marker::I16 => (0, 2),
+      Marker::U32 | Marker::I32 | Marker::F32 => (0, 4),
+      Marker::U64 | Marker::I64 | Marker::F64 => (0, 8),
+      Marker::FixStr(length) if length as usize > max_len => return Err(Error::MessageTooLarge),
+      Marker::FixStr(length) => (0, length as usize),
+      Marker::Str8 | Marker::Bin8 => (1, 0),
+      Marker::Str16 | Marker::Bin16 => (2, 0),
+      Marker::Str32 | Marker::Bin32 => (4, 0),
+      Marker::FixExt1 => (0, 2),
+      Marker::FixExt2 => (0, 3),
+      Marker::FixExt4 => (0, 5),
+      Marker::FixExt8 => (0, 9),
+      Marker::FixExt16 => (0, 17),
+      Marker::Ext8 => (1, 1),
+      Marker::Ext16 => (2, 1),
+      Marker::Ext32 => (4, 1),
+      Marker::FixArray(items) => {
+        if items as usize > max_items {
+          return Err(Error::MessageTooLarge);
+        }
+        remaining = remaining.saturating_add(items as usize);
+        continue;
+      }
+      Marker::FixMap(entries) => {
+        if entries as usize > max_items {
+          return Err(Error::MessageTooLarge);
+        }
+        remaining = remaining.saturating_add(entries as usize * 2);
+        continue;
+      }
+      Marker::Array16 | Marker::Array32 | Marker::Map16 | Marker::Map32 => {
+        let size = match marker {
+          Marker::Array16 | Marker::Map16 => 2,
+          _ => 4,
+        };
+        let Some(items) = read_length(bytes, &mut position, size) else {
+          return Ok(None);
+        };
+        if items > max_items {
+          return Err(Error::MessageTooLarge);
+        }
+        let items = match marker {
+          Marker::Map16 | Marker::Map32 => items.saturating_mul(2),
+          _ => items,
+        };
+        remaining = remaining.saturating_add(items);
+        continue;
+      }
+      Marker::Reserved => return Err(DecodeError::TypeMismatch(marker).into()),
+    };
+    let Some(length) = read_length(bytes, &mut position, length_bytes) else {
+      return Ok(None);
+    };
+    if length > max_len {
+      return Err(Error::MessageTooLarge);
+    }
+    position = position.saturating_add(length).saturating_add(extra);
+  }
+
+  if position > bytes.len() {
+    *scan = FrameScan {
+      remaining,
+      position,
+    };
+    return Ok(None);
+  }
+  Ok(Some(position))
+}
+
+/// Read a big-endian length of `size` bytes at `position`.
+fn read_length(bytes: &[u8], position: &mut usize, size: usize) -> Option<usize> {
+  let length_bytes = bytes.get(*position..*position + size)?;
+  *position += size;
+  Some(
+    length_bytes
+      .iter()
+      .fold(0usize, |length, byte| (length << 8) | *byte as usize),
+  )
+}
+
+/// The path of the first string in the MessagePack value `bytes`
+/// that is not valid UTF-8, e.g. `"params.onion"`.
+pub fn invalid_utf8_field(bytes: &[u8]) -> Option<String> {
+  let value = rmpv::decode::read_value(&mut &bytes[..]).ok()?;
+  find_invalid_utf8(&value, "")
+}
+
+/// `bytes` re-encoded with every string that is not valid UTF-8,
+/// keys included, replaced by its lossy conversion.
+pub fn lossy_utf8(bytes: &[u8]) -> Option<Vec<u8>> {
+  let mut value = rmpv::decode::read_value(&mut &bytes[..]).ok()?;
+  replace_invalid_utf8(&mut value);
+  let mut lossy = vec![];
+  rmpv::encode::write_value(&mut lossy, &value).ok()?;
+  Some(lossy)
+}
+
+fn replace_invalid_utf8(value: &mut rmpv::Value) {
+  match value {
+    rmpv::Value::String(string) if string.is_err() => {
+      let lossy = String::from_utf8_lossy(string.as_bytes()).into_owned();
+      *value = rmpv::Value::from(lossy);
+    }
+    rmpv::Value::Array(items) => items.iter_mut().for_each(replace_invalid_utf8),
+    rmpv::Value::Map(entries) => {
+      for (key, item) in entries {
+        replace_invalid_utf8(key);
+        replace_invalid_utf8(item);
+      }
+    }
+    _ => {}
+  }
+}
+
+fn find_invalid_utf8(value: &rmpv::Value, path: &str) -> Option<String> {
+  match value {
+    rmpv::Value::String(string) if string.is_err() => Some(path.to_string()),
+    rmpv::Value::Array(items) => items
+      .iter()
+      .enumerate()
+      .find_map(|(index, item)| find_invalid_utf8(item, &join_path(path, &index.to_string()))),
+    rmpv::Value::Map(entries) => entries.iter().find_map(|(key, item)| {
+      let key = match key {
+        rmpv::Value::String(string) => match string.as_str() {
+          Some(key) => key.to_string(),
+          None => return Some(join_path(path, &String::from_utf8_lossy(string.as_bytes()))),
+        },
+        key => key.to_string(),
+      };
+      find_invalid_utf8(item, &join_path(path, &key))
+    }),
+    _ => None,
+  }
+}
+
+pub(crate) fn join_path(path: &str, key: &str) -> String {
+  if path.is_empty() {
+    key.to_string()
+  } else {
+    format!("{}.{}", path, key)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_frame_len() {
+    use crate::templates::Announce;
+    use crate::ZeroMessage;
+    use serde_bytes::ByteBuf;
+
+    let announce = Announce {
+      port: 15441,
+      hashes: vec![ByteBuf::from(vec![1u8; 32]); 300],
+      onions: vec!["zp2ynpztyxj2kw7x".to_string(); 20],
+      ..Default::default()
+    };
+    let message = ZeroMessage::request("announce", 0, announce);
+    let bytes = message.to_vec().unwrap();
+    assert_eq!(frame_len(&bytes).unwrap(), Some(bytes.len()));
+    for length in 0..bytes.len() {
+      assert_eq!(frame_len(&bytes[..length]).unwrap(), None);
+    }
+
+    let mut pipelined = bytes.clone();
+    pipelined.extend_from_slice(&bytes);
+    assert_eq!(frame_len(&pipelined).unwrap(), Some(bytes.len()));
+
+    assert!(frame_len(&[0xc1]).is_err());
+  }
+
+  #[test]
+  fn test_scan_frame() {
+    use crate::ZeroMessage;
+
+    let hashes = vec![serde_bytes::ByteBuf::from(vec![1u8; 32]); 300];
+    let message = ZeroMessage::request("announce", 0, hashes);
+    let bytes = message.to_vec().unwrap();
+    let limits = (DEFAULT_MAX_ITEMS, DEFAULT_MAX_LEN);
+
+    // Arriving a byte at a time, as the reader would see it
+    let mut scan = FrameScan::default();
+    for length in 0..bytes.len() {
+      let result = scan_frame(&bytes[..length], &mut scan, limits.0, limits.1);
+      assert_eq!(result.unwrap(), None);
+    }
+    // Every header was read, only the last byte is missing
+    assert_eq!(scan.position, bytes.len());
+    let result = scan_frame(&bytes, &mut scan, limits.0, limits.1);
+    assert_eq!(result.unwrap(), Some(bytes.len()));
+  }
 }
@@ -1,12 +1,104 @@
-use crate::async_connection::Connection;
+use crate::async_connection::{Connection, ReaderLimit};
 use crate::error::Error;
-use crate::message::{Request, Response, ZeroMessage};
+use crate::message::{
+  templates::{
+    Checkport, CheckportResponse, ErrorResponse, Handshake, OkResponse, PingResponse, StreamFile,
+  },
+  Request, Response, ZeroMessage, NOTIFICATION_REQ_ID,
+};
+use crate::util::sleep;
 use crate::PeerAddr;
+use futures::future;
+use futures::stream::{self, Stream};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::any::Any;
+use std::collections::HashMap;
 use std::future::Future;
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Wait for the next request on any of `connections`, resolving to
+/// the index of the connection it came from. Requests arriving
+/// on the other connections stay queued for their next `recv`.
+/// Panics if `connections` is empty.
+pub fn select_recv(
+  connections: &mut [ZeroConnection],
+) -> impl Future<Output = (usize, Result<Request, Error>)> {
+  let futures = connections
+    .iter_mut()
+    .map(|connection| Box::pin(connection.recv()));
+  let select = future::select_all(futures);
+
+  async {
+    let (result, index, _) = select.await;
+    (index, result)
+  }
+}
+
+/// How long the peer took to answer requests of one `cmd`,
+/// see `ZeroConnection::set_record_latency`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+  pub count: usize,
+  pub total: Duration,
+  pub min:   Duration,
+  pub max:   Duration,
+}
+
+impl LatencyStats {
+  fn record(&mut self, latency: Duration) {
+    if self.count == 0 || latency < self.min {
+      self.min = latency;
+    }
+    self.max = self.max.max(latency);
+    self.total += latency;
+    self.count += 1;
+  }
+
+  /// The average latency, zero before any response.
+  pub fn mean(&self) -> Duration {
+    match self.count {
+      0 => Duration::default(),
+      count => self.total / count as u32,
+    }
+  }
+}
+
+/// How `ZeroConnection::connect_with_retry` spaces its attempts:
+/// it waits `base_delay` after the first failure, multiplying
+/// the wait by `multiplier` after each further failure. An
+/// attempt whose handshake is not answered within `timeout`
+/// counts as failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackoffPolicy {
+  pub base_delay:   Duration,
+  pub multiplier:   u32,
+  pub max_attempts: usize,
+  pub timeout:      Duration,
+}
+
+impl Default for BackoffPolicy {
+  fn default() -> BackoffPolicy {
+    BackoffPolicy {
+      base_delay:   Duration::from_secs(1),
+      multiplier:   2,
+      max_attempts: 5,
+      timeout:      Duration::from_secs(10),
+    }
+  }
+}
+
+impl BackoffPolicy {
+  /// The wait after `failures` failed attempts.
+  pub fn delay(&self, failures: usize) -> Duration {
+    let exponent = failures.saturating_sub(1) as u32;
+    let factor = self.multiplier.saturating_pow(exponent);
+    self.base_delay.checked_mul(factor).unwrap_or(Duration::MAX)
+  }
+}
 
 pub struct ZeroConnection {
   /// A ZeroNet Protocol connection
@@ -18,29 +110,55 @@ pub struct ZeroConnection {
   /// ```no_run
   /// use std::net::{TcpStream, TcpListener};
   /// use futures::executor::block_on;
-  ///	use zeronet_protocol::{ZeroConnection, ZeroMessage, PeerAddr};
+  /// use zeronet_protocol::{ZeroConnection, ZeroMessage, PeerAddr};
   ///
   /// fn handle_connection(stream: TcpStream) {
-  ///		let mut connection = ZeroConnection::new(Box::new(stream.try_clone().unwrap()), Box::new(stream)).unwrap();
-  ///		let request = block_on(connection.recv()).unwrap();
+  ///   let mut connection = ZeroConnection::new(Box::new(stream.try_clone().unwrap()), Box::new(stream)).unwrap();
+  ///   let request = block_on(connection.recv()).unwrap();
   ///
-  ///		let body = "anything serializable".to_string();
-  ///		block_on(connection.respond(request.req_id, body));
+  ///   let body = "anything serializable".to_string();
+  ///   block_on(connection.respond(request.req_id, body));
   /// }
   ///
   /// fn main() {
-  /// 	let listener = TcpListener::bind("127.0.0.1:15442").unwrap();
+  ///   let listener = TcpListener::bind("127.0.0.1:15442").unwrap();
   ///
-  /// 	for stream in listener.incoming() {
-  /// 		if let Ok(stream) = stream {
-  /// 			handle_connection(stream)
-  /// 		}
-  /// 	}
+  ///   for stream in listener.incoming() {
+  ///     if let Ok(stream) = stream {
+  ///       handle_connection(stream)
+  ///     }
+  ///   }
   /// }
   /// ```
   pub connection:     Connection<ZeroMessage>,
   pub next_req_id:    Arc<Mutex<usize>>,
   pub target_address: Option<PeerAddr>,
+  /// Arbitrary data attached by the application, shared between clones
+  user_data:          Arc<Mutex<Option<Box<dyn Any + Send>>>>,
+  /// The handshake the peer answered ours with
+  peer_handshake:     Arc<Mutex<Option<Handshake>>>,
+  /// Response latencies by `cmd`, `None` while not recording
+  latency:            Arc<Mutex<Option<HashMap<String, LatencyStats>>>>,
+}
+
+/// One half of a stream shared by the reader and writer,
+/// locked for the duration of each read or write.
+struct SharedStream<S>(Arc<Mutex<S>>);
+
+impl<S: Read> Read for SharedStream<S> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.0.lock().unwrap().read(buf)
+  }
+}
+
+impl<S: Write> Write for SharedStream<S> {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0.lock().unwrap().write(buf)
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    self.0.lock().unwrap().flush()
+  }
 }
 
 impl Clone for ZeroConnection {
@@ -49,6 +167,9 @@ impl Clone for ZeroConnection {
       connection:     self.connection.clone(),
       next_req_id:    self.next_req_id.clone(),
       target_address: self.target_address.clone(),
+      user_data:      self.user_data.clone(),
+      peer_handshake: self.peer_handshake.clone(),
+      latency:        self.latency.clone(),
     }
   }
 }
@@ -64,11 +185,25 @@ impl ZeroConnection {
       connection:     conn,
       next_req_id:    Arc::new(Mutex::new(0)),
       target_address: None,
+      user_data:      Arc::new(Mutex::new(None)),
+      peer_handshake: Arc::new(Mutex::new(None)),
+      latency:        Arc::new(Mutex::new(None)),
     };
 
     Ok(conn)
   }
 
+  /// Creates a new ZeroConnection from a single stream that cannot
+  /// be split into a reader and writer. Writes wait for a read in
+  /// progress, so reads should return `WouldBlock` rather than block
+  /// while no data is available, e.g. through a non-blocking socket.
+  pub fn from_duplex<S: Read + Write + Send + 'static>(stream: S) -> Result<ZeroConnection, Error> {
+    let stream = Arc::new(Mutex::new(stream));
+    let reader = SharedStream(stream.clone());
+    let writer = SharedStream(stream);
+    ZeroConnection::new(Box::new(reader), Box::new(writer))
+  }
+
   /// Creates a new ZeroConnection from a given address
   pub fn from_address(address: PeerAddr) -> Result<ZeroConnection, Error> {
     let (reader, writer) = address.get_pair()?;
@@ -79,43 +214,134 @@ impl ZeroConnection {
 
   /// Connect to an ip and port and perform the handshake,
   /// then return the ZeroConnection.
-  pub fn connect(address: String) -> impl Future<Output = Result<ZeroConnection, Error>> {
-    return async {
-      let address = PeerAddr::parse(address)?;
-      let mut connection = ZeroConnection::from_address(address.clone()).unwrap();
-
-      let mut body = crate::message::templates::Handshake::default();
-      body.target_address = Some(address.to_string());
-      // TODO:
-      // - by default peer_id should be empty string
-      // - peer_id is only generated for clearnet peers
-      body.peer_id = String::new();
-
-      let _resp = connection.request("handshake", body).await?;
-      // TODO: update the connection with information from the handshake
-      // - peer_id
-      // - port
-      // - switch to encrypted connection based on crypt_supported and crypt
-      // - no need for use_bin_type, we won't support deprecated non-binary connections
-      // - what do with onion address?
-
-      Ok(connection)
+  pub async fn connect(address: String) -> Result<ZeroConnection, Error> {
+    let address = PeerAddr::parse(address)?;
+    let mut connection = ZeroConnection::from_address(address.clone()).unwrap();
+
+    // TODO:
+    // - by default peer_id should be empty string
+    // - peer_id is only generated for clearnet peers
+    let body = Handshake {
+      target_address: Some(address.to_string()),
+      peer_id:        String::new(),
+      ..Default::default()
     };
+
+    let _resp = connection.handshake(body).await?;
+    // TODO: update the connection with information from the handshake
+    // - peer_id
+    // - port
+    // - switch to encrypted connection based on crypt_supported and crypt
+    // - no need for use_bin_type, we won't support deprecated non-binary connections
+    // - what do with onion address?
+
+    Ok(connection)
+  }
+
+  /// Dial `address` and send `handshake` until both succeed,
+  /// waiting between attempts as `policy` says. Resolves to the
+  /// first connection that completed its handshake, or the error
+  /// of the last attempt, `DeadlineExceeded` if it timed out.
+  pub fn connect_with_retry(
+    address: PeerAddr,
+    handshake: Handshake,
+    policy: BackoffPolicy,
+  ) -> impl Future<Output = Result<ZeroConnection, Error>> {
+    // Always make at least one attempt
+    let max_attempts = policy.max_attempts.max(1);
+
+    async move {
+      let mut failures = 0;
+      loop {
+        let attempt = async {
+          let mut connection = ZeroConnection::from_address(address.clone())?;
+          connection.handshake(handshake.clone()).await?;
+          Ok(connection)
+        };
+        // A peer may accept the connection but never answer
+        let timeout = sleep(policy.timeout);
+        let err = match future::select(Box::pin(attempt), Box::pin(timeout)).await {
+          future::Either::Left((Ok(connection), _)) => return Ok(connection),
+          future::Either::Left((Err(err), _)) => err,
+          future::Either::Right(_) => Error::DeadlineExceeded,
+        };
+        failures += 1;
+        if failures >= max_attempts {
+          return Err(err);
+        }
+        sleep(policy.delay(failures)).await;
+      }
+    }
+  }
+
+  /// Send our handshake, the returned future resolves to the
+  /// peer's handshake, which is also kept for `peer_handshake`.
+  pub fn handshake(&mut self, body: Handshake) -> impl Future<Output = Result<Handshake, Error>> {
+    let result = self.request("handshake", body);
+    let peer_handshake = self.peer_handshake.clone();
+
+    async move {
+      let handshake: Handshake = result.await?.body()?;
+      *peer_handshake.lock().unwrap() = Some(handshake.clone());
+      Ok(handshake)
+    }
+  }
+
+  /// The handshake the peer answered ours with, including
+  /// any hashfield it attached.
+  pub fn peer_handshake(&self) -> Option<Handshake> {
+    self.peer_handshake.lock().unwrap().clone()
   }
 
   /// Returns a future that will read from the internal reader
   /// and attempt to decode valid ZeroMessages.
   /// The future returns the first Request that gets decoded.
+  ///
+  /// A read of zero bytes means the peer closed the stream: the
+  /// connection is closed and the future returns `ConnectionClosed`.
+  /// Reads failing with `Interrupted` or `WouldBlock` mean no data
+  /// is available yet, they are retried and the future stays pending.
   pub fn recv(&mut self) -> impl Future<Output = Result<Request, Error>> {
     let result = self.connection.recv();
 
-    return async {
+    async {
       match result.await {
         Err(err) => Err(err),
         Ok(ZeroMessage::Response(_)) => Err(Error::UnexpectedResponse),
         Ok(ZeroMessage::Request(req)) => Ok(req),
       }
-    };
+    }
+  }
+
+  /// Returns a stream of incoming requests that ends
+  /// once the connection is closed.
+  /// ```no_run
+  /// use futures::{executor::block_on, StreamExt};
+  /// use zeronet_protocol::ZeroConnection;
+  ///
+  /// let mut connection = block_on(ZeroConnection::connect("127.0.0.1:15441".to_string())).unwrap();
+  /// let mut requests = connection.requests();
+  /// while let Some(request) = block_on(requests.next()) {
+  ///   println!("received {}", request.unwrap().cmd);
+  /// }
+  /// ```
+  pub fn requests(&mut self) -> impl Stream<Item = Result<Request, Error>> + Unpin {
+    Box::pin(stream::unfold(self.clone(), |mut connection| async move {
+      match connection.recv().await {
+        Err(Error::ConnectionClosed) => None,
+        result => Some((result, connection)),
+      }
+    }))
+  }
+
+  /// Send a notification, a request the peer must not respond
+  /// to, so no request id or pending slot is used for it.
+  pub fn notify<T: DeserializeOwned + Serialize>(
+    &mut self,
+    cmd: &str,
+    body: T,
+  ) -> impl Future<Output = Result<(), Error>> {
+    self.connection.send(ZeroMessage::notification(cmd, body))
   }
 
   /// Respond to a request.
@@ -130,11 +356,124 @@ impl ZeroConnection {
     self.connection.send(message)
   }
 
+  /// Respond with `{ "ok": true }`, as to `update` or `setHashfield`.
+  pub fn respond_ok(&mut self, to: usize) -> impl Future<Output = Result<(), Error>> {
+    self.respond(to, OkResponse { ok: true })
+  }
+
+  /// Respond with `{ "ok": false }`.
+  pub fn respond_not_ok(&mut self, to: usize) -> impl Future<Output = Result<(), Error>> {
+    self.respond(to, OkResponse { ok: false })
+  }
+
+  /// Receive one request and answer it with the body `handler`
+  /// returns for it. When the handler fails the peer is sent an
+  /// `ErrorResponse` and the handler's error is returned.
+  /// Notifications are handled but never responded to.
+  pub fn recv_and_respond<F, R>(&mut self, handler: F) -> impl Future<Output = Result<(), Error>>
+  where
+    F: FnOnce(Request) -> Result<R, Error>,
+    R: DeserializeOwned + Serialize,
+  {
+    let request = self.recv();
+    let mut connection = self.clone();
+
+    async move {
+      let request = request.await?;
+      if request.is_notification() {
+        return handler(request).map(|_| ());
+      }
+      let to = request.req_id;
+      match handler(request) {
+        Ok(body) => connection.respond(to, body).await,
+        Err(err) => {
+          let body = ErrorResponse {
+            error: err.to_string(),
+          };
+          connection.respond(to, body).await?;
+          Err(err)
+        }
+      }
+    }
+  }
+
+  /// Respond to request `to` with the body `handler` resolves to,
+  /// or with an `ErrorResponse` if it fails or takes longer than
+  /// `deadline`. A late handler is dropped and `DeadlineExceeded`
+  /// returned, a failed handler's error is returned. Nothing is
+  /// written when `to` is a notification's `req_id`.
+  pub fn respond_within<F, R>(
+    &mut self,
+    to: usize,
+    deadline: Duration,
+    handler: F,
+  ) -> impl Future<Output = Result<(), Error>>
+  where
+    F: Future<Output = Result<R, Error>>,
+    R: DeserializeOwned + Serialize,
+  {
+    let timeout = sleep(deadline);
+    let mut connection = self.clone();
+
+    async move {
+      let result = match future::select(Box::pin(handler), Box::pin(timeout)).await {
+        future::Either::Left((result, _)) => result,
+        future::Either::Right(_) => Err(Error::DeadlineExceeded),
+      };
+      if to == NOTIFICATION_REQ_ID {
+        return result.map(|_| ());
+      }
+      match result {
+        Ok(body) => connection.respond(to, body).await,
+        Err(err) => {
+          let body = ErrorResponse {
+            error: err.to_string(),
+          };
+          connection.respond(to, body).await?;
+          Err(err)
+        }
+      }
+    }
+  }
+
+  /// Respond to a request with `header` followed by the raw
+  /// `body` bytes, announced through its `stream_bytes` field.
+  /// This is the counterpart to `stream_file`.
+  pub fn respond_streaming<T: DeserializeOwned + Serialize>(
+    &mut self,
+    to: usize,
+    header: T,
+    body: ByteBuf,
+  ) -> impl Future<Output = Result<(), Error>> {
+    let mut message = ZeroMessage::response(to, header);
+    if let ZeroMessage::Response(response) = &mut message {
+      response.set_stream_bytes(body.len());
+    }
+    self.connection.send_with_buf(message, body.into_vec())
+  }
+
   /// Returns a future that will send a request with
   /// a new `req_id` and then read from internal reader
   /// and attempt to decode valid ZeroMessages.
   /// The future returns the first Response that
   /// has the corresponding `to` field.
+  ///
+  /// Requests received in the meantime are queued for `recv`,
+  /// so clones of one connection can request and answer
+  /// the peer's requests at the same time.
+  /// ```no_run
+  /// use futures::{executor::block_on, future::join};
+  /// use zeronet_protocol::ZeroConnection;
+  ///
+  /// let mut connection = block_on(ZeroConnection::connect("127.0.0.1:15441".to_string())).unwrap();
+  /// let mut server = connection.clone();
+  /// let answer = async move {
+  ///   while let Ok(request) = server.recv().await {
+  ///     server.respond(request.req_id, ()).await.unwrap();
+  ///   }
+  /// };
+  /// let (_, pong) = block_on(join(answer, connection.request_ping()));
+  /// ```
   pub fn request<T: DeserializeOwned + Serialize>(
     &mut self,
     cmd: &str,
@@ -143,13 +482,185 @@ impl ZeroConnection {
     let message = ZeroMessage::request(cmd, self.req_id(), body);
     let result = self.connection.request(message);
 
-    return async {
+    self.response(cmd, result)
+  }
+
+  /// Like `request`, but `buf` is written raw right after the
+  /// request and announced by `stream_bytes` in its params,
+  /// for large `update` or `setHashfield` payloads.
+  pub fn request_with_buf<T: DeserializeOwned + Serialize>(
+    &mut self,
+    cmd: &str,
+    body: T,
+    buf: ByteBuf,
+  ) -> impl Future<Output = Result<Response, Error>> {
+    let mut message = ZeroMessage::request(cmd, self.req_id(), body);
+    if let ZeroMessage::Request(request) = &mut message {
+      request.set_stream_bytes(buf.len());
+    }
+    let result = self.connection.request_with_buf(message, buf.into_vec());
+
+    self.response(cmd, result)
+  }
+
+  /// Wait for the response to a request of `cmd`, recording
+  /// its latency if enabled.
+  fn response(
+    &self,
+    cmd: &str,
+    result: impl Future<Output = Result<ZeroMessage, Error>>,
+  ) -> impl Future<Output = Result<Response, Error>> {
+    let latency = self.latency.clone();
+    let cmd = cmd.to_string();
+    let sent = Instant::now();
+
+    async move {
       match result.await {
         Err(err) => Err(err),
-        Ok(ZeroMessage::Response(res)) => Ok(res),
+        Ok(ZeroMessage::Response(res)) => {
+          if let Some(stats) = latency.lock().unwrap().as_mut() {
+            stats.entry(cmd).or_default().record(sent.elapsed());
+          }
+          Ok(res)
+        }
         Ok(ZeroMessage::Request(_)) => Err(Error::UnexpectedRequest),
       }
+    }
+  }
+
+  /// Send a `ping` request, the returned future resolves
+  /// to the peer's `PingResponse`.
+  pub fn request_ping(&mut self) -> impl Future<Output = Result<PingResponse, Error>> {
+    let result = self.request("ping", ());
+
+    async { result.await?.body() }
+  }
+
+  /// Ask the peer to connect back to us on `port` with `checkport`,
+  /// the returned future resolves to whether the port is open.
+  pub fn check_my_port(&mut self, port: u16) -> impl Future<Output = Result<bool, Error>> {
+    let result = self.request("checkport", Checkport { port });
+
+    async {
+      let response: CheckportResponse = result.await?.body()?;
+      Ok(response.is_open())
+    }
+  }
+
+  /// Request a file with `streamFile`, the returned future
+  /// resolves to a reader over the `stream_bytes` raw bytes
+  /// the peer sent following its response.
+  pub fn stream_file(
+    &mut self,
+    site: &str,
+    inner_path: &str,
+    size: usize,
+  ) -> impl Future<Output = Result<Cursor<Vec<u8>>, Error>> {
+    let body = StreamFile {
+      site: site.to_string(),
+      inner_path: inner_path.to_string(),
+      size,
     };
+    let result = self.request("streamFile", body);
+
+    async {
+      let mut response = result.await?;
+      let stream = response.take_stream().ok_or(Error::MissingStream)?;
+      Ok(Cursor::new(stream))
+    }
+  }
+
+  /// Close the connection if nothing was sent or received for
+  /// longer than `max_idle`, returns whether it was idle.
+  pub fn check_idle(&self, max_idle: Duration) -> bool {
+    self.connection.check_idle(max_idle)
+  }
+
+  /// Like `check_idle`, measured up to `now`.
+  pub fn check_idle_at(&self, now: Instant, max_idle: Duration) -> bool {
+    self.connection.check_idle_at(now, max_idle)
+  }
+
+  /// Write requests and responses in the order they were made,
+  /// regardless of the order their futures are polled in.
+  pub fn set_ordered_sends(&self, ordered: bool) {
+    self.connection.set_ordered_sends(ordered);
+  }
+
+  /// Drop the oldest of more than `limit` unsent messages, which
+  /// also orders sends, see `Connection::set_send_limit`.
+  pub fn set_send_limit(&self, limit: Option<usize>) {
+    self.connection.set_send_limit(limit);
+  }
+
+  /// Limit how many requests may await their response at once,
+  /// see `Connection::set_max_pending`.
+  pub fn set_max_pending(&self, max_pending: Option<usize>) {
+    self.connection.set_max_pending(max_pending);
+  }
+
+  /// Limit how many messages per second `recv` returns,
+  /// see `Connection::set_rate_limit`.
+  pub fn set_rate_limit(&self, per_second: Option<u32>) {
+    self.connection.set_rate_limit(per_second);
+  }
+
+  /// Limit the items of arrays and maps the peer may send,
+  /// see `Connection::set_max_items`.
+  pub fn set_max_items(&self, max_items: usize) {
+    self.connection.set_max_items(max_items);
+  }
+
+  /// Limit the length of other values the peer may send,
+  /// see `Connection::set_max_len`.
+  pub fn set_max_len(&self, max_len: usize) {
+    self.connection.set_max_len(max_len);
+  }
+
+  /// Share a cap on reader threads with other connections,
+  /// see `Connection::set_reader_limit`.
+  pub fn set_reader_limit(&self, limit: Option<ReaderLimit>) {
+    self.connection.set_reader_limit(limit);
+  }
+
+  /// Start or stop recording how long the peer takes to answer
+  /// requests, by `cmd`. Stopping discards what was recorded.
+  pub fn set_record_latency(&self, record: bool) {
+    let mut latency = self.latency.lock().unwrap();
+    if !record {
+      *latency = None;
+    } else if latency.is_none() {
+      *latency = Some(HashMap::new());
+    }
+  }
+
+  /// The latencies recorded since `set_record_latency`, by `cmd`.
+  pub fn latency_stats(&self) -> HashMap<String, LatencyStats> {
+    self.latency.lock().unwrap().clone().unwrap_or_default()
+  }
+
+  /// Close the connection once its last clone is dropped,
+  /// waking any pending futures with `ConnectionClosed`.
+  pub fn set_close_on_drop(&self, close_on_drop: bool) {
+    self.connection.set_close_on_drop(close_on_drop);
+  }
+
+  /// Attach `data` to the connection, replacing any previous data.
+  pub fn set_user_data<D: Any + Send>(&self, data: D) {
+    *self.user_data.lock().unwrap() = Some(Box::new(data));
+  }
+
+  /// A copy of the attached data, if it is of type `D`.
+  pub fn user_data<D: Any + Send + Clone>(&self) -> Option<D> {
+    let user_data = self.user_data.lock().unwrap();
+    user_data.as_ref()?.downcast_ref::<D>().cloned()
+  }
+
+  /// Run `f` on the attached data, if it is of type `D`,
+  /// e.g. to update a counter in place.
+  pub fn with_user_data<D: Any + Send, R>(&self, f: impl FnOnce(&mut D) -> R) -> Option<R> {
+    let mut user_data = self.user_data.lock().unwrap();
+    user_data.as_mut()?.downcast_mut::<D>().map(f)
   }
 
   /// Get the req_id of the last request
@@ -168,135 +679,672 @@ impl ZeroConnection {
 #[cfg(test)]
 mod tests {
   use super::ZeroConnection;
-  use crate::ZeroMessage;
-  use futures::executor::block_on;
+  use crate::async_connection::ReaderLimit;
+  use crate::{
+    requestable::Requestable,
+    templates::{
+      CheckportResponse, ErrorResponse, Handshake, OkResponse, PingResponse, StreamFileResponse,
+    },
+    ZeroMessage,
+  };
+  use crate::testing::{duplex, pipe};
+  use futures::{executor::block_on, StreamExt};
+  use serde_bytes::ByteBuf;
   use std::{
     io::{Error, ErrorKind, Read, Result, Write},
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::mpsc::channel,
+    time::Duration,
   };
 
-  struct ChannelWriter {
-    tx:     Sender<Vec<u8>>,
-    buffer: Option<Vec<u8>>,
+  #[test]
+  fn test_connection() {
+    let (mut server, mut client) = duplex();
+    let request = client.request("ping", String::new());
+    std::thread::spawn(move || {
+      block_on(request).unwrap();
+    });
+    let request = block_on(server.recv());
+    assert!(request.is_ok());
+  }
+
+  #[test]
+  fn test_request_ping() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      assert_eq!(request.cmd, "ping");
+      let body = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      block_on(server.respond(request.req_id, body)).unwrap();
+    });
+    let response = block_on(client.request_ping()).unwrap();
+    assert_eq!(response.body, "Pong!");
   }
 
-  impl ChannelWriter {
-    fn new(tx: Sender<Vec<u8>>) -> ChannelWriter {
-      ChannelWriter { tx, buffer: None }
-    }
+  #[test]
+  fn test_handshake_hashfield() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      assert_eq!(request.cmd, "handshake");
+      let mut body = Handshake::new();
+      body.hashfield_raw = Some(ByteBuf::from(vec![1, 0, 0x39, 0x30]));
+      block_on(server.respond(request.req_id, body)).unwrap();
+    });
+
+    assert!(client.peer_handshake().is_none());
+    let handshake = block_on(client.handshake(Handshake::new())).unwrap();
+    assert_eq!(handshake.hashfield(), vec![1, 12345]);
+    assert_eq!(client.peer_handshake().unwrap().hashfield(), vec![1, 12345]);
   }
 
-  impl Write for ChannelWriter {
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-      let mut buffer = match self.buffer.take() {
-        Some(buffer) => buffer,
-        None => vec![],
-      };
-      buffer.append(&mut buf.to_vec());
-      self.buffer = Some(buffer);
+  #[test]
+  fn test_request_with_buf() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      let mut request = block_on(server.recv()).unwrap();
+      assert_eq!(request.cmd, "update");
+      let size = request.take_stream().unwrap().len();
+      let body = serde_json::json!({ "size": size });
+      block_on(server.respond(request.req_id, body)).unwrap();
+    });
 
-      // TODO: rmp-serde does not flush the write
-      // remove this once this is corrected
-      self.flush()?;
+    let buf = ByteBuf::from(vec![7; 1000]);
+    let response = block_on(client.request_with_buf("update", (), buf)).unwrap();
+    let body: serde_json::Value = response.body().unwrap();
+    assert_eq!(body["size"], 1000);
+  }
 
-      return Ok(buf.len());
-    }
+  #[test]
+  fn test_check_idle() {
+    let (mut server, client) = duplex();
+    let max_idle = Duration::from_secs(60);
+    let start = client.connection.last_active();
+    assert!(!client.check_idle_at(start + Duration::from_secs(30), max_idle));
+    assert!(!client.connection.is_closed());
 
-    fn flush(&mut self) -> Result<()> {
-      if let Some(buffer) = self.buffer.take() {
-        self
-          .tx
-          .send(buffer)
-          .map_err(|_| Error::new(ErrorKind::NotConnected, "Could not send on channel"))?;
-      }
-      Ok(())
-    }
+    std::thread::spawn(move || block_on(server.request_ping()));
+    let mut recv = client.clone();
+    block_on(recv.recv()).unwrap();
+    let active = client.connection.last_active();
+    assert!(active >= start);
+
+    assert!(!client.check_idle_at(active + Duration::from_secs(30), max_idle));
+    assert!(client.check_idle_at(active + Duration::from_secs(61), max_idle));
+    assert!(client.connection.is_closed());
   }
 
-  struct ChannelReader {
-    rx:     Receiver<Vec<u8>>,
-    buffer: Option<Vec<u8>>,
+  #[test]
+  fn test_recv_and_respond() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      block_on(server.recv_and_respond(|request| {
+        assert_eq!(request.cmd, "ping");
+        Ok(PingResponse {
+          body: "Pong!".to_string(),
+        })
+      }))
+      .unwrap();
+      let handler = |_| -> std::result::Result<(), _> { Err(crate::Error::UnexpectedRequest) };
+      let result = block_on(server.recv_and_respond(handler));
+      assert!(matches!(result, Err(crate::Error::UnexpectedRequest)));
+    });
+
+    let response = block_on(client.request_ping()).unwrap();
+    assert_eq!(response.body, "Pong!");
+    let response = block_on(client.request("foo", ())).unwrap();
+    let body: ErrorResponse = response.body().unwrap();
+    assert_eq!(body.error, "Unexpectedly received a request");
   }
 
-  impl ChannelReader {
-    fn new(rx: Receiver<Vec<u8>>) -> ChannelReader {
-      ChannelReader { rx, buffer: None }
+  #[test]
+  fn test_recv_and_respond_notification() {
+    let (reader, mut writer) = pipe();
+    let (mut responses, response_writer) = pipe();
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(response_writer)).unwrap();
+    for _ in 0..2 {
+      let notification = ZeroMessage::notification("pex", ());
+      writer.write_all(&notification.to_vec().unwrap()).unwrap();
     }
+
+    block_on(server.recv_and_respond(|_| Ok(()))).unwrap();
+    let handler = |_| -> std::result::Result<(), _> { Err(crate::Error::UnexpectedRequest) };
+    let result = block_on(server.recv_and_respond(handler));
+    assert!(matches!(result, Err(crate::Error::UnexpectedRequest)));
+
+    // Nothing was written back, not even the error
+    responses.set_nonblocking(true);
+    let result = responses.read(&mut [0; 1]);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
   }
 
-  impl Read for ChannelReader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-      let mut buffer = match self.buffer.take() {
-        Some(buffer) => buffer,
-        None => vec![],
-      };
-      while let Ok(mut res) = self.rx.try_recv() {
-        buffer.append(&mut res);
-      }
-      if buffer.len() == 0 {
-        return Err(Error::from(ErrorKind::Interrupted));
-      }
-      let length = std::cmp::min(buf.len(), buffer.len());
-      let mut iterator = buffer.into_iter();
-      for i in 0..length {
-        if let Some(byte) = iterator.next() {
-          buf[i] = byte;
-        }
+  #[test]
+  fn test_max_pending() {
+    let (mut server, mut client) = duplex();
+    client.set_max_pending(Some(2));
+
+    let first = client.request_ping();
+    let second = client.request_ping();
+    let third = block_on(client.request_ping());
+    assert!(matches!(third, Err(crate::Error::TooManyRequests)));
+
+    std::thread::spawn(move || {
+      for _ in 0..3 {
+        let request = block_on(server.recv()).unwrap();
+        let body = PingResponse {
+          body: "Pong!".to_string(),
+        };
+        block_on(server.respond(request.req_id, body)).unwrap();
       }
-      self.buffer = Some(iterator.collect());
-      Ok(length)
-    }
+    });
+    let (first, second) = block_on(futures::future::join(first, second));
+    assert!(first.is_ok() && second.is_ok());
+
+    // Answered requests free their slots
+    assert!(block_on(client.request_ping()).is_ok());
   }
 
-  fn create_pair() -> (ZeroConnection, ZeroConnection) {
-    let (tx1, rx1) = channel();
-    let (tx2, rx2) = channel();
-    let conn1 = ZeroConnection::new(
-      Box::new(ChannelReader::new(rx2)),
-      Box::new(ChannelWriter::new(tx1)),
-    );
-    let conn2 = ZeroConnection::new(
-      Box::new(ChannelReader::new(rx1)),
-      Box::new(ChannelWriter::new(tx2)),
-    );
-    (conn1.unwrap(), conn2.unwrap())
+  #[test]
+  fn test_dropped_request() {
+    let (mut server, mut client) = duplex();
+    client.set_max_pending(Some(1));
+    std::thread::spawn(move || {
+      for _ in 0..2 {
+        let request = block_on(server.recv()).unwrap();
+        let pong = PingResponse {
+          body: "Pong!".to_string(),
+        };
+        block_on(server.respond(request.req_id, pong)).unwrap();
+      }
+    });
+
+    // Sent, but given up on before the response arrives
+    let mut pending = Box::pin(client.request_ping());
+    block_on(async {
+      let _ = futures::poll!(pending.as_mut());
+    });
+    assert_eq!(client.connection.pending_request_count(), 1);
+    drop(pending);
+    assert_eq!(client.connection.pending_request_count(), 0);
+
+    assert!(block_on(client.request_ping()).is_ok());
   }
 
   #[test]
-  fn test_connection() {
-    let (mut server, mut client) = create_pair();
-    let request = client.request("ping", String::new());
+  fn test_tap() {
+    use crate::async_connection::Direction;
+
+    let (mut server, mut client) = duplex();
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let moved_seen = seen.clone();
+    client.connection.set_tap(move |direction, bytes| {
+      moved_seen.lock().unwrap().push((direction, bytes.to_vec()));
+    });
+
+    let pong = PingResponse {
+      body: "Pong!".to_string(),
+    };
+    let response = ZeroMessage::response(0, pong).to_vec().unwrap();
     std::thread::spawn(move || {
-      block_on(request).unwrap();
+      let request = block_on(server.recv()).unwrap();
+      let pong = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      block_on(server.respond(request.req_id, pong)).unwrap();
     });
-    let request = block_on(server.recv());
-    assert!(request.is_ok());
+    block_on(client.request_ping()).unwrap();
+
+    let ping = ZeroMessage::request("ping", 0, ()).to_vec().unwrap();
+    let seen = seen.lock().unwrap();
+    assert_eq!(
+      *seen,
+      vec![(Direction::Outgoing, ping), (Direction::Incoming, response)]
+    );
   }
 
   #[test]
-  fn multiple_receivers() {
-    let (mut server1, mut client) = create_pair();
-    let mut server2 = server1.clone();
+  fn test_spawner() {
+    use crate::async_connection::Spawner;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingSpawner(Arc<AtomicUsize>);
+
+    impl Spawner for CountingSpawner {
+      fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        std::thread::spawn(task);
+      }
+    }
 
+    let (mut server, mut client) = duplex();
+    let count = Arc::new(AtomicUsize::new(0));
+    let spawner = CountingSpawner(count.clone());
+    client.connection.set_spawner(spawner);
     std::thread::spawn(move || {
-      block_on(client.connection.send(ZeroMessage::request("ping", 0, ()))).unwrap();
-      block_on(client.connection.send(ZeroMessage::request("ping", 1, ()))).unwrap();
-      block_on(client.connection.send(ZeroMessage::request("ping", 2, ()))).unwrap();
-      block_on(client.connection.send(ZeroMessage::request("ping", 3, ()))).unwrap();
+      let request = block_on(server.recv()).unwrap();
+      let pong = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      block_on(server.respond(request.req_id, pong)).unwrap();
     });
+    block_on(client.request_ping()).unwrap();
+
+    // At least one write and one read
+    assert!(count.load(Ordering::SeqCst) >= 2);
+  }
+
+  #[test]
+  fn test_latency_stats() {
+    let (mut server, mut client) = duplex();
+    client.set_record_latency(true);
     std::thread::spawn(move || {
-      block_on(server1.recv()).ok().unwrap();
-      block_on(server1.recv()).ok().unwrap();
+      for _ in 0..3 {
+        let request = block_on(server.recv()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let pong = PingResponse {
+          body: "Pong!".to_string(),
+        };
+        block_on(server.respond(request.req_id, pong)).unwrap();
+      }
     });
-    block_on(server2.recv()).ok().unwrap();
-    let result = block_on(server2.recv());
-    assert!(result.is_ok());
+    block_on(client.request_ping()).unwrap();
+    block_on(client.request_ping()).unwrap();
+    block_on(client.request("getFile", ())).unwrap();
+
+    let stats = client.latency_stats();
+    assert_eq!(stats.len(), 2);
+    let ping = stats["ping"];
+    assert_eq!(ping.count, 2);
+    assert!(ping.min >= Duration::from_millis(5));
+    assert!(ping.min <= ping.mean() && ping.mean() <= ping.max);
+    assert_eq!(stats["getFile"].count, 1);
+
+    client.set_record_latency(false);
+    assert!(client.latency_stats().is_empty());
   }
 
   #[test]
-  fn multiple_clients() {
-    let (mut server, mut client1) = create_pair();
-    let client2 = client1.clone();
+  fn test_backoff_policy() {
+    use super::BackoffPolicy;
+
+    let policy = BackoffPolicy {
+      base_delay:   Duration::from_millis(100),
+      multiplier:   3,
+      max_attempts: 4,
+      timeout:      Duration::from_secs(1),
+    };
+    assert_eq!(policy.delay(1), Duration::from_millis(100));
+    assert_eq!(policy.delay(2), Duration::from_millis(300));
+    assert_eq!(policy.delay(3), Duration::from_millis(900));
+  }
+
+  #[test]
+  fn test_connect_with_retry() {
+    use super::BackoffPolicy;
+    use crate::PeerAddr;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = PeerAddr::from(listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+      // Hang up on the first attempt before it is answered
+      drop(listener.accept().unwrap());
+      let (stream, _) = listener.accept().unwrap();
+      let reader = Box::new(stream.try_clone().unwrap());
+      let mut server = ZeroConnection::new(reader, Box::new(stream)).unwrap();
+      let request = block_on(server.recv()).unwrap();
+      block_on(server.respond(request.req_id, Handshake::new())).unwrap();
+    });
+
+    let policy = BackoffPolicy {
+      base_delay:   Duration::from_millis(10),
+      multiplier:   2,
+      max_attempts: 3,
+      timeout:      Duration::from_secs(10),
+    };
+    let connect = ZeroConnection::connect_with_retry(address, Handshake::new(), policy);
+    let connection = block_on(connect).unwrap();
+    assert!(connection.peer_handshake().is_some());
+  }
+
+  #[test]
+  fn test_connect_with_retry_gives_up() {
+    use super::BackoffPolicy;
+    use crate::PeerAddr;
+    use std::net::TcpListener;
+
+    // Nothing listens on the port once the listener is dropped
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = PeerAddr::from(listener.local_addr().unwrap());
+    drop(listener);
+
+    let policy = BackoffPolicy {
+      base_delay:   Duration::from_millis(1),
+      multiplier:   2,
+      max_attempts: 2,
+      timeout:      Duration::from_secs(10),
+    };
+    let connect = ZeroConnection::connect_with_retry(address, Handshake::new(), policy);
+    assert!(block_on(connect).is_err());
+  }
+
+  #[test]
+  fn test_connect_with_retry_timeout() {
+    use super::BackoffPolicy;
+    use crate::PeerAddr;
+    use std::net::TcpListener;
+
+    // Accepts every attempt but never answers the handshake
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = PeerAddr::from(listener.local_addr().unwrap());
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        tx.send(stream.unwrap()).unwrap();
+      }
+    });
+
+    let policy = BackoffPolicy {
+      base_delay:   Duration::from_millis(1),
+      multiplier:   2,
+      max_attempts: 2,
+      timeout:      Duration::from_millis(50),
+    };
+    let connect = ZeroConnection::connect_with_retry(address, Handshake::new(), policy);
+    let result = block_on(connect);
+    assert!(matches!(result, Err(crate::Error::DeadlineExceeded)));
+    // Both attempts reached the peer
+    assert_eq!(rx.iter().take(2).count(), 2);
+  }
+
+  #[test]
+  fn test_rate_limit() {
+    let (mut server, mut client) = duplex();
+    // A token per second leaves a second for the first two `recv`s
+    server.set_rate_limit(Some(1));
+    for req_id in 0..2 {
+      let ping = ZeroMessage::request("ping", req_id, ());
+      block_on(client.connection.send(ping)).unwrap();
+    }
+
+    assert_eq!(block_on(server.recv()).unwrap().req_id, 0);
+    let result = block_on(server.recv());
+    assert!(matches!(result, Err(crate::Error::RateLimited)));
+
+    std::thread::sleep(Duration::from_millis(1500));
+    assert_eq!(block_on(server.recv()).unwrap().req_id, 1);
+  }
+
+  #[test]
+  fn test_token_bucket() {
+    use crate::async_connection::TokenBucket;
+    use std::time::Instant;
+
+    let mut bucket = TokenBucket::new(2);
+    let start = Instant::now();
+    assert!(bucket.take(start));
+    assert!(bucket.take(start));
+    assert!(!bucket.take(start));
+    assert!(!bucket.take(start + Duration::from_millis(400)));
+    assert!(bucket.take(start + Duration::from_millis(600)));
+    // Refills only up to a second's worth
+    let later = start + Duration::from_secs(10);
+    assert!(bucket.take(later) && bucket.take(later));
+    assert!(!bucket.take(later));
+  }
+
+  #[test]
+  fn test_reader_limit() {
+    let limit = ReaderLimit::new(1);
+    let (mut first, mut first_client) = duplex();
+    let (mut second, mut second_client) = duplex();
+    first.set_reader_limit(Some(limit.clone()));
+    second.set_reader_limit(Some(limit.clone()));
+    let first = std::thread::spawn(move || block_on(first.recv()).unwrap());
+    let second = std::thread::spawn(move || block_on(second.recv()).unwrap());
+
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(limit.running(), 1);
+
+    let ping = ZeroMessage::request("ping", 1, ());
+    block_on(first_client.connection.send(ping.clone())).unwrap();
+    block_on(second_client.connection.send(ping)).unwrap();
+    assert_eq!(first.join().unwrap().req_id, 1);
+    assert_eq!(second.join().unwrap().req_id, 1);
+  }
+
+  #[test]
+  fn test_respond_within() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      let fast = block_on(server.recv()).unwrap();
+      let pong = async {
+        Ok(PingResponse {
+          body: "Pong!".to_string(),
+        })
+      };
+      let deadline = Duration::from_secs(5);
+      block_on(server.respond_within(fast.req_id, deadline, pong)).unwrap();
+
+      let slow = block_on(server.recv()).unwrap();
+      let handler = async {
+        crate::util::sleep(Duration::from_secs(5)).await;
+        Ok(())
+      };
+      let deadline = Duration::from_millis(20);
+      let result = block_on(server.respond_within(slow.req_id, deadline, handler));
+      assert!(matches!(result, Err(crate::Error::DeadlineExceeded)));
+    });
+
+    assert!(block_on(client.request_ping()).is_ok());
+    let response = block_on(client.request("getFile", ())).unwrap();
+    let body: ErrorResponse = response.body().unwrap();
+    assert_eq!(body.error, crate::Error::DeadlineExceeded.to_string());
+  }
+
+  #[test]
+  fn test_respond_within_notification() {
+    let (mut responses, writer) = pipe();
+    let mut server = ZeroConnection::new(Box::new(std::io::empty()), Box::new(writer)).unwrap();
+    let to = super::NOTIFICATION_REQ_ID;
+
+    let deadline = Duration::from_secs(5);
+    block_on(server.respond_within(to, deadline, async { Ok(()) })).unwrap();
+    let handler = async { std::result::Result::<(), _>::Err(crate::Error::UnexpectedRequest) };
+    let result = block_on(server.respond_within(to, deadline, handler));
+    assert!(matches!(result, Err(crate::Error::UnexpectedRequest)));
+
+    responses.set_nonblocking(true);
+    let result = responses.read(&mut [0; 1]);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
+  }
+
+  #[test]
+  fn test_respond_ok() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      block_on(server.respond_ok(request.req_id)).unwrap();
+      let request = block_on(server.recv()).unwrap();
+      block_on(server.respond_not_ok(request.req_id)).unwrap();
+    });
+
+    let response = block_on(client.request("update", ())).unwrap();
+    let json = serde_json::to_value(ZeroMessage::Response(response)).unwrap();
+    let expected = serde_json::json!({ "cmd": "response", "to": 0, "ok": true });
+    assert_eq!(json, expected);
+    let response = block_on(client.request("setHashfield", ())).unwrap();
+    let body: OkResponse = response.body().unwrap();
+    assert!(!body.ok);
+
+    let body: OkResponse = serde_json::from_str(r#"{"ok": "Updated"}"#).unwrap();
+    assert!(body.ok);
+  }
+
+  #[test]
+  fn test_notify() {
+    let (mut server, mut client) = duplex();
+    block_on(client.notify("pex", ())).unwrap();
+    let shared_state = client.connection.shared_state.lock().unwrap();
+    assert!(shared_state.requests.is_empty());
+    drop(shared_state);
+    assert_eq!(*client.next_req_id.lock().unwrap(), 0);
+
+    let request = block_on(server.recv()).unwrap();
+    assert_eq!(request.cmd, "pex");
+    assert!(request.is_notification());
+  }
+
+  #[test]
+  fn test_quiesce() {
+    let (mut server, mut client) = duplex();
+    let pending = client.request_ping();
+    assert_eq!(client.connection.pending_request_count(), 1);
+
+    client.connection.quiesce();
+    let result = block_on(client.request_ping());
+    assert!(matches!(result, Err(crate::Error::ShuttingDown)));
+    assert!(!client.connection.is_closed());
+
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      let pong = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      block_on(server.respond(request.req_id, pong)).unwrap();
+    });
+    assert!(block_on(pending).is_ok());
+    assert_eq!(client.connection.pending_request_count(), 0);
+    assert!(client.connection.is_closed());
+  }
+
+  #[test]
+  fn test_quiesce_dropped_request() {
+    let (_server, mut client) = duplex();
+    drop(client.request_ping());
+    client.connection.quiesce();
+    assert!(client.connection.is_closed());
+
+    // Dropped while the connection waits for it
+    let (_server, mut client) = duplex();
+    let pending = client.request_ping();
+    client.connection.quiesce();
+    assert!(!client.connection.is_closed());
+    drop(pending);
+    assert!(client.connection.is_closed());
+  }
+
+  #[test]
+  fn test_pending_request_count_after_close() {
+    let (server, mut client) = duplex();
+    let mut pending = Box::pin(client.request_ping());
+    block_on(async {
+      let _ = futures::poll!(pending.as_mut());
+    });
+    assert_eq!(client.connection.pending_request_count(), 1);
+
+    drop(server);
+    let result = block_on(client.recv());
+    assert!(matches!(result, Err(crate::Error::ConnectionClosed)));
+    assert_eq!(client.connection.pending_request_count(), 0);
+    assert!(block_on(pending).is_err());
+  }
+
+  #[test]
+  fn test_duplicate_req_id() {
+    let (mut server, mut client) = duplex();
+    let ping = || ZeroMessage::request("ping", 7, ());
+    let first = client.connection.request(ping());
+    let second = block_on(client.connection.request(ping()));
+    assert!(matches!(second, Err(crate::Error::DuplicateReqId)));
+
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      let pong = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      block_on(server.respond(request.req_id, pong)).unwrap();
+    });
+    let response = block_on(first).unwrap();
+    assert_eq!(response.to(), Some(7));
+  }
+
+  #[test]
+  fn test_select_recv() {
+    let (mut first_peer, first) = duplex();
+    let (mut second_peer, second) = duplex();
+    let mut connections = [first, second];
+
+    std::thread::spawn(move || block_on(second_peer.request_ping()));
+    let (index, request) = block_on(super::select_recv(&mut connections));
+    assert_eq!(index, 1);
+    assert_eq!(request.unwrap().cmd, "ping");
+
+    std::thread::spawn(move || block_on(first_peer.request("getFile", ())));
+    let (index, request) = block_on(super::select_recv(&mut connections));
+    assert_eq!(index, 0);
+    assert_eq!(request.unwrap().cmd, "getFile");
+  }
+
+  #[test]
+  fn test_check_my_port() {
+    for status in &["open", "closed"] {
+      let (mut server, mut client) = duplex();
+      std::thread::spawn(move || {
+        let request = block_on(server.recv()).unwrap();
+        assert_eq!(request.cmd, "checkport");
+        let body = CheckportResponse {
+          status:      status.to_string(),
+          ip_external: "1.2.3.4".to_string(),
+        };
+        block_on(server.respond(request.req_id, body)).unwrap();
+      });
+      let is_open = block_on(client.check_my_port(15441)).unwrap();
+      assert_eq!(is_open, *status == "open");
+    }
+  }
+
+  #[test]
+  fn test_requests_stream() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      for req_id in 0..3 {
+        block_on(client.connection.send(ZeroMessage::request("ping", req_id, ()))).unwrap();
+      }
+    });
+
+    let requests: Vec<_> = block_on(server.requests().collect());
+    assert_eq!(requests.len(), 3);
+    assert!(requests.iter().all(|request| request.is_ok()));
+    assert!(server.connection.is_closed());
+  }
+
+  #[test]
+  fn multiple_receivers() {
+    let (mut server1, mut client) = duplex();
+    let mut server2 = server1.clone();
+
+    std::thread::spawn(move || {
+      block_on(client.connection.send(ZeroMessage::request("ping", 0, ()))).unwrap();
+      block_on(client.connection.send(ZeroMessage::request("ping", 1, ()))).unwrap();
+      block_on(client.connection.send(ZeroMessage::request("ping", 2, ()))).unwrap();
+      block_on(client.connection.send(ZeroMessage::request("ping", 3, ()))).unwrap();
+    });
+    std::thread::spawn(move || {
+      block_on(server1.recv()).ok().unwrap();
+      block_on(server1.recv()).ok().unwrap();
+    });
+    block_on(server2.recv()).ok().unwrap();
+    let result = block_on(server2.recv());
+    assert!(result.is_ok());
+  }
+
+  #[test]
+  fn multiple_clients() {
+    let (mut server, mut client1) = duplex();
+    let client2 = client1.clone();
 
     std::thread::spawn(move || {
       block_on(client1.request("ping", ())).unwrap();
@@ -304,4 +1352,678 @@ mod tests {
     let result = block_on(server.recv()).ok().unwrap();
     assert!(result.req_id == client2.last_req_id());
   }
+
+  #[test]
+  fn test_stream_file() {
+    let (client_reader, mut server_writer) = pipe();
+    let (mut server_reader, client_writer) = pipe();
+    let mut client = ZeroConnection::new(Box::new(client_reader), Box::new(client_writer)).unwrap();
+
+    std::thread::spawn(move || {
+      let request: ZeroMessage = rmp_serde::from_read(&mut server_reader).unwrap();
+      assert_eq!(request.req_id(), Some(0));
+
+      let body = b"streamed file content".to_vec();
+      let response = ZeroMessage::response(
+        0,
+        StreamFileResponse {
+          stream_bytes: body.len(),
+        },
+      );
+      server_writer
+        .write_all(&rmp_serde::to_vec_named(&response).unwrap())
+        .unwrap();
+      server_writer.write_all(&body).unwrap();
+    });
+
+    let mut stream = block_on(client.stream_file("1ADDR", "content.json", 21)).unwrap();
+    let mut content = String::new();
+    stream.read_to_string(&mut content).unwrap();
+    assert_eq!(content, "streamed file content");
+  }
+
+  struct FlakyReader {
+    chunks: Vec<Result<Vec<u8>>>,
+  }
+
+  impl Read for FlakyReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      if self.chunks.is_empty() {
+        return Ok(0);
+      }
+      match self.chunks.remove(0) {
+        Ok(mut chunk) => {
+          let length = std::cmp::min(buf.len(), chunk.len());
+          buf[..length].copy_from_slice(&chunk[..length]);
+          if length < chunk.len() {
+            self.chunks.insert(0, Ok(chunk.split_off(length)));
+          }
+          Ok(length)
+        }
+        Err(err) => Err(err),
+      }
+    }
+  }
+
+  #[test]
+  fn test_respond_streaming() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      assert_eq!(request.cmd, "streamFile");
+      let header = serde_json::json!({ "location": 0, "size": 21 });
+      let body = ByteBuf::from(b"streamed file content".to_vec());
+      block_on(server.respond_streaming(request.req_id, header, body)).unwrap();
+    });
+
+    let mut response = block_on(client.request("streamFile", ())).unwrap();
+    assert_eq!(response.stream_bytes(), Some(21));
+    let header: serde_json::Value = response.body().unwrap();
+    assert_eq!(header["size"], 21);
+    assert_eq!(
+      response.take_stream(),
+      Some(b"streamed file content".to_vec())
+    );
+  }
+
+  fn answer_and_request(
+    connection: ZeroConnection,
+    count: usize,
+  ) -> (Vec<crate::message::Request>, Vec<PingResponse>) {
+    let mut server = connection.clone();
+    let mut client = connection;
+    let answer = async move {
+      let mut requests = vec![];
+      for _ in 0..count {
+        let request = server.recv().await.unwrap();
+        let body = PingResponse {
+          body: "Pong!".to_string(),
+        };
+        server.respond(request.req_id, body).await.unwrap();
+        requests.push(request);
+      }
+      requests
+    };
+    let request = async move {
+      let mut responses = vec![];
+      for _ in 0..count {
+        responses.push(client.request_ping().await.unwrap());
+      }
+      responses
+    };
+    block_on(futures::future::join(answer, request))
+  }
+
+  #[test]
+  fn test_interleaved_requests() {
+    let (first, second) = duplex();
+    let handle = std::thread::spawn(move || answer_and_request(second, 10));
+    let (requests, responses) = answer_and_request(first, 10);
+    assert_eq!(requests.len(), 10);
+    assert!(responses.iter().all(|response| response.body == "Pong!"));
+
+    let (requests, responses) = handle.join().unwrap();
+    assert!(requests.iter().all(|request| request.cmd == "ping"));
+    assert_eq!(responses.len(), 10);
+  }
+
+  #[test]
+  fn test_respond_during_recv() {
+    let (mut server, mut client) = duplex();
+    let mut requester = client.clone();
+    let first = std::thread::spawn(move || block_on(requester.request_ping()));
+    let request = block_on(server.recv()).unwrap();
+
+    // Start the next recv, it keeps the reader busy until a message arrives
+    let mut receiver = server.clone();
+    let (result_tx, result_rx) = channel();
+    std::thread::spawn(move || {
+      result_tx.send(block_on(receiver.recv())).unwrap();
+    });
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let body = PingResponse {
+      body: "Pong!".to_string(),
+    };
+    block_on(server.respond(request.req_id, body)).unwrap();
+    assert_eq!(first.join().unwrap().unwrap().body, "Pong!");
+
+    block_on(client.connection.send(ZeroMessage::request("ping", 5, ()))).unwrap();
+    let request = result_rx.recv().unwrap().unwrap();
+    assert_eq!(request.req_id, 5);
+  }
+
+  #[test]
+  fn test_unknown_response_ignored() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      block_on(server.respond(request.req_id + 100, ())).unwrap();
+      let body = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      block_on(server.respond(request.req_id, body)).unwrap();
+    });
+    let response = block_on(client.request_ping()).unwrap();
+    assert_eq!(response.body, "Pong!");
+  }
+
+  #[test]
+  fn test_ordered_sends() {
+    let (mut server, mut client) = duplex();
+    client.set_ordered_sends(true);
+    let handle = std::thread::spawn(move || {
+      let mut req_ids = vec![];
+      for _ in 0..3 {
+        let request = block_on(server.recv()).unwrap();
+        block_on(server.respond(request.req_id, ())).unwrap();
+        req_ids.push(request.req_id);
+      }
+      req_ids
+    });
+
+    let first = client.request("getFile", ());
+    let second = client.request("getFile", ());
+    let third = client.request("getFile", ());
+    // Polled in reverse, but still written in call order
+    let (third, second, first) = block_on(futures::future::join3(third, second, first));
+    assert!(first.is_ok() && second.is_ok() && third.is_ok());
+    assert_eq!(handle.join().unwrap(), vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn test_send_limit() {
+    let (mut server, mut client) = duplex();
+    client.set_ordered_sends(true);
+    client.set_send_limit(Some(2));
+
+    let pexes: Vec<_> = (0..3)
+      .map(|req_id| {
+        let pex = ZeroMessage::request("pex", req_id, ());
+        client.connection.send(pex)
+      })
+      .collect();
+    let results = block_on(futures::future::join_all(pexes));
+    assert!(matches!(results[0], Err(crate::Error::SendDropped)));
+    assert!(results[1].is_ok() && results[2].is_ok());
+
+    assert_eq!(block_on(server.recv()).unwrap().req_id, 1);
+    assert_eq!(block_on(server.recv()).unwrap().req_id, 2);
+
+    // The limit alone is enough to queue and drop
+    let (mut server, mut client) = duplex();
+    client.set_send_limit(Some(1));
+    let first = client.connection.send(ZeroMessage::request("pex", 0, ()));
+    let second = client.connection.send(ZeroMessage::request("pex", 1, ()));
+    let (first, second) = block_on(futures::future::join(first, second));
+    assert!(matches!(first, Err(crate::Error::SendDropped)));
+    assert!(second.is_ok());
+    assert_eq!(block_on(server.recv()).unwrap().req_id, 1);
+  }
+
+  #[test]
+  fn test_close_on_drop() {
+    let (mut server, _client) = duplex();
+    server.set_close_on_drop(true);
+    let clone = server.clone();
+
+    let pending = server.recv();
+    let (result_tx, result_rx) = channel();
+    std::thread::spawn(move || {
+      result_tx.send(block_on(pending)).unwrap();
+    });
+    let timeout = std::time::Duration::from_millis(50);
+    assert!(result_rx.recv_timeout(timeout).is_err());
+
+    drop(server);
+    assert!(result_rx.recv_timeout(timeout).is_err());
+    drop(clone);
+    let result = result_rx.recv().unwrap();
+    assert!(matches!(result, Err(crate::Error::ConnectionClosed)));
+  }
+
+  #[test]
+  fn test_user_data() {
+    #[derive(Clone, Debug, PartialEq)]
+    struct PeerState {
+      last_seen:    u64,
+      bad_messages: usize,
+    }
+
+    let (connection, _) = duplex();
+    assert_eq!(connection.user_data::<PeerState>(), None);
+
+    connection.set_user_data(PeerState {
+      last_seen:    1234,
+      bad_messages: 0,
+    });
+    let clone = connection.clone();
+    clone.with_user_data(|state: &mut PeerState| state.bad_messages += 1);
+
+    let state = connection.user_data::<PeerState>().unwrap();
+    assert_eq!(state.last_seen, 1234);
+    assert_eq!(state.bad_messages, 1);
+    assert_eq!(connection.user_data::<String>(), None);
+  }
+
+  #[test]
+  fn test_partial_reads() {
+    let bytes = rmp_serde::to_vec_named(&ZeroMessage::request("ping", 0, ())).unwrap();
+    let (first, second) = bytes.split_at(bytes.len() / 2);
+    let reader = FlakyReader {
+      chunks: vec![
+        Ok(first.to_vec()),
+        Err(Error::from(ErrorKind::Interrupted)),
+        Err(Error::from(ErrorKind::WouldBlock)),
+        Ok(second.to_vec()),
+      ],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+
+    let request = block_on(server.recv()).unwrap();
+    assert_eq!(request.cmd, "ping");
+  }
+
+  #[test]
+  fn test_pipelined_messages() {
+    let mut bytes = rmp_serde::to_vec_named(&ZeroMessage::request("ping", 0, ())).unwrap();
+    let second = ZeroMessage::request("getFile", 1, serde_json::json!({ "site": "1ADDR" }));
+    bytes.append(&mut rmp_serde::to_vec_named(&second).unwrap());
+    let reader = FlakyReader {
+      chunks: vec![Ok(bytes)],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+
+    let first = block_on(server.recv()).unwrap();
+    assert_eq!(first.cmd, "ping");
+    let second = block_on(server.recv()).unwrap();
+    assert_eq!(second.cmd, "getFile");
+    assert_eq!(second.req_id, 1);
+  }
+
+  #[test]
+  fn test_stream_followed_by_message() {
+    let body = b"streamed file content".to_vec();
+    let header = StreamFileResponse {
+      stream_bytes: body.len(),
+    };
+    let mut bytes = ZeroMessage::response(0, header).to_vec().unwrap();
+    bytes.extend_from_slice(&body);
+    // The next message arrives in the same read as the stream
+    bytes.append(&mut ZeroMessage::request("ping", 5, ()).to_vec().unwrap());
+    let reader = FlakyReader {
+      chunks: vec![Ok(bytes)],
+    };
+    let mut client = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+
+    let stream = block_on(client.stream_file("1ADDR", "content.json", body.len()));
+    assert_eq!(stream.unwrap().into_inner(), body);
+    let request = block_on(client.recv()).unwrap();
+    assert_eq!(request.cmd, "ping");
+    assert_eq!(request.req_id, 5);
+  }
+
+  #[test]
+  fn test_invalid_utf8() {
+    // A request whose `cmd` is not valid UTF-8
+    let mut bytes = vec![];
+    rmp::encode::write_map_len(&mut bytes, 2).unwrap();
+    rmp::encode::write_str(&mut bytes, "cmd").unwrap();
+    rmp::encode::write_str_len(&mut bytes, 4).unwrap();
+    bytes.extend_from_slice(&[0x70, 0xff, 0xfe, 0x67]);
+    rmp::encode::write_str(&mut bytes, "req_id").unwrap();
+    rmp::encode::write_uint(&mut bytes, 1).unwrap();
+    bytes.append(&mut rmp_serde::to_vec_named(&ZeroMessage::request("ping", 2, ())).unwrap());
+    let reader = FlakyReader {
+      chunks: vec![Ok(bytes)],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+
+    let result = block_on(server.recv());
+    assert!(matches!(result, Err(crate::Error::InvalidUtf8(field)) if field == "cmd"));
+    assert!(!server.connection.is_closed());
+    let request = block_on(server.recv()).unwrap();
+    assert_eq!(request.cmd, "ping");
+  }
+
+  #[test]
+  fn test_invalid_utf8_response() {
+    let (reader, mut writer) = pipe();
+    let mut client = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+    let pending = client.request("handshake", ());
+
+    // A response to it whose `cmd` is not valid UTF-8, fields of
+    // the body are kept as bytes and only fail decoding the body
+    let mut bytes = vec![];
+    rmp::encode::write_map_len(&mut bytes, 2).unwrap();
+    rmp::encode::write_str(&mut bytes, "cmd").unwrap();
+    rmp::encode::write_str_len(&mut bytes, 4).unwrap();
+    bytes.extend_from_slice(&[0x72, 0xff, 0xfe, 0x65]);
+    rmp::encode::write_str(&mut bytes, "to").unwrap();
+    rmp::encode::write_uint(&mut bytes, 0).unwrap();
+    bytes.append(&mut rmp_serde::to_vec_named(&ZeroMessage::request("ping", 1, ())).unwrap());
+    writer.write_all(&bytes).unwrap();
+
+    let result = block_on(pending);
+    assert!(matches!(result, Err(crate::Error::InvalidUtf8(field)) if field == "cmd"));
+    // Other receivers only see what was meant for them
+    let request = block_on(client.recv()).unwrap();
+    assert_eq!(request.cmd, "ping");
+  }
+
+  #[test]
+  fn test_reset_reader() {
+    let garbage = vec![0xc1, 0x00, 0x13, 0x37];
+    let valid = rmp_serde::to_vec_named(&ZeroMessage::request("ping", 0, ())).unwrap();
+    let reader = FlakyReader {
+      chunks: vec![Ok(garbage), Ok(valid)],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+
+    assert!(block_on(server.recv()).is_err());
+    assert!(server.connection.is_closed());
+
+    server.connection.reset_reader().unwrap();
+    assert!(!server.connection.is_closed());
+    let request = block_on(server.recv()).unwrap();
+    assert_eq!(request.cmd, "ping");
+
+    // Closed by the peer rather than by a decode error
+    let result = block_on(server.recv());
+    assert!(matches!(result, Err(crate::Error::ConnectionClosed)));
+    server.connection.reset_reader().unwrap();
+    assert!(server.connection.is_closed());
+  }
+
+  #[test]
+  fn test_reset_reader_pending_request() {
+    let (reader, mut writer) = pipe();
+    let mut client = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+    writer.write_all(&[0xc1, 0x00, 0x13, 0x37]).unwrap();
+    assert!(block_on(client.recv()).is_err());
+
+    // Waiting for a response across the reset
+    let pending = client.request_ping();
+    assert_eq!(client.connection.pending_request_count(), 1);
+    client.connection.reset_reader().unwrap();
+    assert_eq!(client.connection.pending_request_count(), 0);
+
+    for to in 0..2 {
+      let pong = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      let response = ZeroMessage::response(to, pong);
+      writer.write_all(&response.to_vec().unwrap()).unwrap();
+    }
+    let result = block_on(pending);
+    assert!(matches!(result, Err(crate::Error::ConnectionClosed)));
+    // The stale response is skipped rather than taken for this one
+    assert!(block_on(client.request_ping()).is_ok());
+  }
+
+  #[test]
+  fn test_reset_reader_while_reading() {
+    let (mut server, mut client) = duplex();
+    let mut reading = server.clone();
+    let handle = std::thread::spawn(move || block_on(reading.recv()));
+    std::thread::sleep(Duration::from_millis(50));
+
+    let result = server.connection.reset_reader();
+    assert!(matches!(result, Err(crate::Error::ReadInProgress)));
+    block_on(client.connection.send(ZeroMessage::request("ping", 0, ()))).unwrap();
+    assert_eq!(handle.join().unwrap().unwrap().cmd, "ping");
+  }
+
+  #[test]
+  fn test_last_error() {
+    let garbage = vec![0xc1, 0x00, 0x13, 0x37];
+    let reader = FlakyReader {
+      chunks: vec![Ok(garbage)],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+    assert!(server.connection.last_error().is_none());
+
+    assert!(block_on(server.recv()).is_err());
+    assert!(server.connection.is_closed());
+    let error = server.connection.last_error().unwrap();
+    assert!(matches!(*error, crate::Error::InvalidMessagePack(_)));
+
+    server.connection.reset_reader().unwrap();
+    assert!(server.connection.last_error().is_none());
+  }
+
+  #[test]
+  fn test_max_items() {
+    // { "params": [...] } declaring 2^32 - 1 items that never arrive
+    let mut huge = vec![0x81, 0xa6];
+    huge.extend_from_slice(b"params");
+    huge.extend_from_slice(&[0xdd, 0xff, 0xff, 0xff, 0xff]);
+    let reader = FlakyReader {
+      chunks: vec![Ok(huge.clone())],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+    assert!(block_on(server.recv()).is_err());
+    let error = server.connection.last_error().unwrap();
+    assert!(matches!(*error, crate::Error::MessageTooLarge));
+    let result = ZeroMessage::from_slice(&huge);
+    assert!(matches!(result, Err(crate::Error::MessageTooLarge)));
+
+    let ping = rmp_serde::to_vec_named(&ZeroMessage::request("ping", 0, ())).unwrap();
+    let reader = FlakyReader {
+      chunks: vec![Ok(ping)],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+    server.set_max_items(1);
+    assert!(block_on(server.recv()).is_err());
+    let error = server.connection.last_error().unwrap();
+    assert!(matches!(*error, crate::Error::MessageTooLarge));
+  }
+
+  #[test]
+  fn test_max_len() {
+    // { "params": <bin> } declaring 2^32 - 1 bytes that never arrive
+    let mut huge = vec![0x81, 0xa6];
+    huge.extend_from_slice(b"params");
+    huge.extend_from_slice(&[0xc6, 0xff, 0xff, 0xff, 0xff]);
+    let reader = FlakyReader {
+      chunks: vec![Ok(huge.clone())],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+    assert!(block_on(server.recv()).is_err());
+    let error = server.connection.last_error().unwrap();
+    assert!(matches!(*error, crate::Error::MessageTooLarge));
+    let result = ZeroMessage::from_slice(&huge);
+    assert!(matches!(result, Err(crate::Error::MessageTooLarge)));
+
+    let ping = rmp_serde::to_vec_named(&ZeroMessage::request("ping", 0, ())).unwrap();
+    let reader = FlakyReader {
+      chunks: vec![Ok(ping)],
+    };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+    server.set_max_len(2);
+    assert!(block_on(server.recv()).is_err());
+    let error = server.connection.last_error().unwrap();
+    assert!(matches!(*error, crate::Error::MessageTooLarge));
+  }
+
+  #[test]
+  fn test_eof_closes() {
+    let reader = FlakyReader { chunks: vec![] };
+    let mut server = ZeroConnection::new(Box::new(reader), Box::new(std::io::sink())).unwrap();
+
+    let result = block_on(server.recv());
+    assert!(matches!(result, Err(crate::Error::ConnectionClosed)));
+    assert!(server.connection.is_closed());
+    assert!(server.connection.last_error().is_none());
+    let result = block_on(server.recv());
+    assert!(matches!(result, Err(crate::Error::ConnectionClosed)));
+  }
+
+  #[test]
+  fn test_empty_read_retried() {
+    let (mut server, mut client) = duplex();
+    let (result_tx, result_rx) = channel();
+    std::thread::spawn(move || {
+      result_tx.send(block_on(server.recv())).unwrap();
+    });
+
+    // Nothing was sent yet, so every read is empty but the stream is open
+    let timeout = std::time::Duration::from_millis(50);
+    assert!(result_rx.recv_timeout(timeout).is_err());
+
+    block_on(client.connection.send(ZeroMessage::request("ping", 0, ()))).unwrap();
+    let request = result_rx.recv().unwrap().unwrap();
+    assert_eq!(request.cmd, "ping");
+  }
+
+  /// Both ends of a pipe in a single stream, the reader
+  /// must not block so writes can take their turn.
+  struct PipeStream(crate::testing::ChannelReader, crate::testing::ChannelWriter);
+
+  impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      self.0.read(buf)
+    }
+  }
+
+  impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+      self.1.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+      self.1.flush()
+    }
+  }
+
+  #[test]
+  fn test_from_duplex() {
+    let (reader1, writer2) = pipe();
+    let (mut reader2, writer1) = pipe();
+    let mut server = ZeroConnection::new(Box::new(reader1), Box::new(writer1)).unwrap();
+    reader2.set_nonblocking(true);
+    let mut client = ZeroConnection::from_duplex(PipeStream(reader2, writer2)).unwrap();
+
+    std::thread::spawn(move || {
+      let request = block_on(server.recv()).unwrap();
+      let pong = PingResponse {
+        body: "Pong!".to_string(),
+      };
+      block_on(server.respond(request.req_id, pong)).unwrap();
+    });
+    let response = block_on(client.request_ping()).unwrap();
+    assert_eq!(response.body, "Pong!");
+  }
+
+  /// Records every write separately.
+  #[derive(Clone, Default)]
+  struct RecordingWriter(std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>);
+
+  impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+      self.0.lock().unwrap().push(buf.to_vec());
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_send_batch() {
+    let (reader, _writer) = pipe();
+    let writes = RecordingWriter::default();
+    let mut conn = ZeroConnection::new(Box::new(reader), Box::new(writes.clone())).unwrap();
+
+    let messages: Vec<_> = (0..3)
+      .map(|req_id| ZeroMessage::request("pex", req_id, ()))
+      .collect();
+    block_on(conn.connection.send_batch(messages.clone())).unwrap();
+
+    let writes = writes.0.lock().unwrap();
+    assert_eq!(writes.len(), 1);
+    let (decoded, rest) = ZeroMessage::from_slice_many(&writes[0]).unwrap();
+    assert_eq!(decoded, messages);
+    assert!(rest.is_empty());
+  }
+
+  #[test]
+  fn test_send_batch_received() {
+    let (mut server, mut client) = duplex();
+    let messages = (0..3)
+      .map(|req_id| ZeroMessage::request("ping", req_id, ()))
+      .collect();
+    block_on(client.connection.send_batch(messages)).unwrap();
+
+    for req_id in 0..3 {
+      let request = block_on(server.recv()).unwrap();
+      assert_eq!(request.req_id, req_id);
+    }
+  }
+
+  #[test]
+  fn test_request_batch() {
+    let (mut server, mut client) = duplex();
+    std::thread::spawn(move || {
+      for _ in 0..3 {
+        let request = block_on(server.recv()).unwrap();
+        let pong = PingResponse {
+          body: format!("Pong {}!", request.req_id),
+        };
+        block_on(server.respond(request.req_id, pong)).unwrap();
+      }
+    });
+
+    let messages = (0..3)
+      .map(|req_id| ZeroMessage::request("ping", req_id, ()))
+      .collect();
+    let responses = block_on(client.connection.request_batch(messages)).unwrap();
+    for (req_id, response) in responses.into_iter().enumerate() {
+      let pong: PingResponse = response.unwrap().body().unwrap();
+      assert_eq!(pong.body, format!("Pong {}!", req_id));
+    }
+    assert_eq!(client.connection.pending_request_count(), 0);
+
+    // A rejected request keeps the whole batch from being sent
+    client.set_max_pending(Some(1));
+    let messages = (3..5)
+      .map(|req_id| ZeroMessage::request("ping", req_id, ()))
+      .collect();
+    let result = block_on(client.connection.request_batch(messages));
+    assert!(matches!(result, Err(crate::Error::TooManyRequests)));
+    assert_eq!(client.connection.pending_request_count(), 0);
+  }
+
+  struct BrokenWriter;
+
+  impl Write for BrokenWriter {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+      Err(Error::from(ErrorKind::BrokenPipe))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+      Err(Error::from(ErrorKind::BrokenPipe))
+    }
+  }
+
+  #[test]
+  fn test_failed_write_closes() {
+    let (reader, _writer) = pipe();
+    let mut conn = ZeroConnection::new(Box::new(reader), Box::new(BrokenWriter)).unwrap();
+
+    let mut receiver = conn.clone();
+    let (result_tx, result_rx) = channel();
+    std::thread::spawn(move || {
+      result_tx.send(block_on(receiver.recv())).unwrap();
+    });
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    assert!(block_on(conn.respond(0, ())).is_err());
+    assert!(conn.connection.is_closed());
+    let error = conn.connection.last_error().unwrap();
+    assert!(matches!(*error, crate::Error::Io(_)));
+    let result = result_rx.recv().unwrap();
+    assert!(matches!(result, Err(crate::Error::ConnectionClosed)));
+  }
 }